@@ -0,0 +1,32 @@
+//! Compares `eval` on an expression with calls through `autodiff_fn!`, on the
+//! composition from `main.rs`
+//!
+//! Run with `cargo bench --bench autodiff_fn`
+
+use std::hint::black_box;
+use std::time::Instant;
+
+use autodiff::{autodiff_fn, Fn, X};
+
+const INPUTS: usize = 20_000_000;
+
+fn time(name: &str, mut f: impl FnMut(f32) -> (f32, f32)) {
+    let start = Instant::now();
+    let mut sum = 0.0;
+
+    for i in 0..INPUTS {
+        let (y, dy) = f(black_box(i as f32 * 1e-6));
+        sum += y + dy;
+    }
+
+    black_box(sum);
+    println!("{name:>12}: {:?}", start.elapsed());
+}
+
+fn main() {
+    let h = (X.pow(3.0) / 2.0 + (2.0 * X).sin()).compose(X / 3.0 - 5.0);
+    let fused = autodiff_fn!((X.pow(3.0) / 2.0 + (2.0 * X).sin()).compose(X / 3.0 - 5.0));
+
+    time("eval", |x| h.eval(x));
+    time("autodiff_fn", |x| black_box(fused)(x));
+}
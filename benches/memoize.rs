@@ -0,0 +1,51 @@
+//! Compares `g.sin() * g.cos()` for an expensive `g` with and without sharing
+//! the evaluation of `g`
+//!
+//! Run with `cargo bench --bench memoize`
+
+use std::hint::black_box;
+use std::time::Instant;
+
+use autodiff::{compose_chain, Fn, X};
+
+const INPUTS: usize = 1_000_000;
+
+fn time(name: &str, f: impl Fn + Copy) {
+    let start = Instant::now();
+    let mut sum = 0.0;
+
+    for i in 0..INPUTS {
+        let (y, dy) = f.eval(black_box(i as f32 * 1e-6));
+        sum += y + dy;
+    }
+
+    black_box(sum);
+    println!("{name:>16}: {:?}", start.elapsed());
+}
+
+fn main() {
+    let layer = (X.exp() + X.sin().exp() + X.cos().exp()).ln().atan().sin();
+
+    // Fully inlined, the compiler can already evaluate the shared layer once
+    let shared = layer.memoize();
+    let (sin, cos) = layer.sincos();
+
+    time("inlined g", layer);
+    time("inlined plain", layer.sin() * layer.cos());
+    time(
+        "inlined memoize",
+        (shared.sin() * shared.cos()).memo_scope(),
+    );
+    time("inlined sincos", (sin * cos).memo_scope());
+
+    // The chain is walked in a loop, which the compiler doesn't deduplicate
+    let layers = [layer; 8];
+    let g = compose_chain(&layers);
+    let shared = g.memoize();
+    let (sin, cos) = g.sincos();
+
+    time("chain g", g);
+    time("chain plain", g.sin() * g.cos());
+    time("chain memoize", (shared.sin() * shared.cos()).memo_scope());
+    time("chain sincos", (sin * cos).memo_scope());
+}
@@ -0,0 +1,31 @@
+//! Compares evaluating an expression directly against evaluating its table
+//!
+//! Run with `cargo bench --bench tabulate`
+
+use std::hint::black_box;
+use std::time::Instant;
+
+use autodiff::{Fn, X};
+
+const INPUTS: usize = 10_000_000;
+
+fn time(name: &str, f: impl Fn + Copy) {
+    let start = Instant::now();
+    let mut sum = 0.0;
+
+    for i in 0..INPUTS {
+        let (y, dy) = f.eval(black_box(i as f32 * 8e-7 - 4.0));
+        sum += y + dy;
+    }
+
+    black_box(sum);
+    println!("{name:>8}: {:?}", start.elapsed());
+}
+
+fn main() {
+    let f = (X.sin() * (X * X * -0.25).exp() + (X * X + 1.0).ln().atan()) * X.cos().pow(2.0);
+    let table = f.tabulate(-4.0, 4.0, 1024);
+
+    time("direct", f);
+    time("table", table.expr());
+}
@@ -1,7 +1,38 @@
-use std::ops::{Add, Div, Mul, Neg, Sub};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::ops::{Add, Div, Mul, Neg, Rem, Sub};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::LocalKey;
+
+mod rng;
+
+use rng::Rng;
 
 pub trait Fn {
     fn eval(self, input: f32) -> (f32, f32);
+
+    /// The log of a positive value and the log-derivative, `(ln(f), f'/f)`
+    ///
+    /// Products, quotients, powers and exponentials override this to combine
+    /// their operands in log space, so their intermediate values never
+    /// overflow. Compositions and the pass-through wrappers `memoize`,
+    /// `memo_scope`, `scale_grad` and `sanitize` forward it to their operand.
+    /// Any other op uses this default, so an overflow inside it isn't avoided.
+    fn eval_log(self, input: f32) -> (f32, f32)
+    where
+        Self: Sized,
+    {
+        let (y, dy) = self.eval(input);
+
+        (y.ln(), dy / y)
+    }
+}
+
+/// The result of an evaluation with named fields
+#[derive(Clone, Copy, Debug)]
+pub struct Eval {
+    pub value: f32,
+    pub derivative: f32,
 }
 
 // TODO: Multiple variables using vector
@@ -28,6 +59,13 @@ impl Fn for Const {
     }
 }
 
+/// Plain functions, such as the ones made by `autodiff_fn!`
+impl Fn for fn(f32) -> (f32, f32) {
+    fn eval(self, input: f32) -> (f32, f32) {
+        self(input)
+    }
+}
+
 /// Adding 2 expressions
 #[derive(Clone, Copy)]
 pub struct AddOp<T1: Fn, T2: Fn> {
@@ -90,6 +128,14 @@ impl<T1: Fn, T2: Fn> Fn for MulOp<T1, T2> {
 
         (u * v, u * dv + v * du)
     }
+
+    // ln(f) = ln(u) + ln(v), f'/f = u'/u + v'/v
+    fn eval_log(self, input: f32) -> (f32, f32) {
+        let (u, du) = self.lhs.eval_log(input);
+        let (v, dv) = self.rhs.eval_log(input);
+
+        (u + v, du + dv)
+    }
 }
 
 /// Dividing 2 expressions
@@ -107,6 +153,54 @@ impl<T1: Fn, T2: Fn> Fn for DivOp<T1, T2> {
 
         (u / v, (du * v - dv * u) / (v * v))
     }
+
+    // ln(f) = ln(u) - ln(v), f'/f = u'/u - v'/v
+    fn eval_log(self, input: f32) -> (f32, f32) {
+        let (u, du) = self.lhs.eval_log(input);
+        let (v, dv) = self.rhs.eval_log(input);
+
+        (u - v, du - dv)
+    }
+}
+
+/// Wrapping an expression into `[0, period)`
+///
+/// The value jumps back by `period` whenever it crosses a multiple of
+/// `period`; the derivative ignores these discontinuities and passes `u'`
+/// through unchanged.
+#[derive(Clone, Copy)]
+pub struct RemOp<T: Fn> {
+    expr: T,
+    period: f32,
+}
+
+impl<T: Fn> Fn for RemOp<T> {
+    // f(x) = u mod p, f'(x) = u'
+    fn eval(self, input: f32) -> (f32, f32) {
+        let (y, dy) = self.expr.eval(input);
+
+        (y.rem_euclid(self.period), dy)
+    }
+}
+
+/// Euclidean division of an expression, the quotient matching `%`
+///
+/// `u == u.floor_div(p) * p + u % p` holds between the jumps at multiples of
+/// `p`. The quotient is piecewise constant, so its derivative is 0 everywhere
+/// except at these jumps, where it is undefined.
+#[derive(Clone, Copy)]
+pub struct FloorDivOp<T: Fn> {
+    expr: T,
+    divisor: f32,
+}
+
+impl<T: Fn> Fn for FloorDivOp<T> {
+    // f(x) = floor(u / p) for p > 0, f'(x) = 0
+    fn eval(self, input: f32) -> (f32, f32) {
+        let (y, _) = self.expr.eval(input);
+
+        (y.div_euclid(self.divisor), 0.0)
+    }
 }
 
 // Power
@@ -126,6 +220,89 @@ impl<T: Fn> Fn for PowOp<T> {
             dy * self.order * y.powf(self.order - 1.0),
         )
     }
+
+    // ln(f) = n ln(u), f'/f = nu'/u
+    fn eval_log(self, input: f32) -> (f32, f32) {
+        let (y, dy) = self.expr.eval_log(input);
+
+        (self.order * y, self.order * dy)
+    }
+}
+
+/// Power of an expression clamped to at least `floor`
+#[derive(Clone, Copy)]
+pub struct PowClampedOp<T: Fn> {
+    expr: T,
+    order: f32,
+    floor: f32,
+}
+
+impl<T: Fn> Fn for PowClampedOp<T> {
+    // f(x) = max(u, m)^n, f'(x) = u'nu^(n - 1) above the floor and 0 below it
+    fn eval(self, input: f32) -> (f32, f32) {
+        let (y, dy) = self.expr.eval(input);
+
+        if y < self.floor {
+            return (self.floor.powf(self.order), 0.0);
+        }
+
+        (
+            y.powf(self.order),
+            dy * self.order * y.powf(self.order - 1.0),
+        )
+    }
+}
+
+/// Sign-preserving power `sign(u)|u|^p`, defined for negative `u`
+///
+/// For `p < 1` the derivative at `u = 0` is infinite, or NaN if `u'` is 0.
+#[derive(Clone, Copy)]
+pub struct SignedPowOp<T: Fn> {
+    expr: T,
+    order: f32,
+}
+
+impl<T: Fn> Fn for SignedPowOp<T> {
+    // f(x) = sign(u)|u|^p, f'(x) = u'p|u|^(p - 1)
+    fn eval(self, input: f32) -> (f32, f32) {
+        let (y, dy) = self.expr.eval(input);
+        let abs = y.abs();
+
+        (
+            y.signum() * abs.powf(self.order),
+            dy * self.order * abs.powf(self.order - 1.0),
+        )
+    }
+}
+
+/// Half-integer power `u^(n/2)`, computed as `sqrt(u)^n`
+///
+/// Like `sqrt`, the result is NaN for `u < 0`, even when `n` is even.
+#[derive(Clone, Copy)]
+pub struct PowHalfOp<T: Fn> {
+    expr: T,
+    numerator: i32,
+}
+
+impl<T: Fn> Fn for PowHalfOp<T> {
+    // f(x) = u^(n/2), f'(x) = u'(n/2)u^(n/2 - 1)
+    fn eval(self, input: f32) -> (f32, f32) {
+        let (y, dy) = self.expr.eval(input);
+        let sqrt = y.sqrt();
+
+        (
+            sqrt.powi(self.numerator),
+            dy * 0.5 * self.numerator as f32 * sqrt.powi(self.numerator - 2),
+        )
+    }
+
+    // ln(f) = (n/2)ln(u), f'/f = (n/2)u'/u
+    fn eval_log(self, input: f32) -> (f32, f32) {
+        let (y, dy) = self.expr.eval_log(input);
+        let order = 0.5 * self.numerator as f32;
+
+        (order * y, order * dy)
+    }
 }
 
 // Exponentation
@@ -142,9 +319,74 @@ impl<T: Fn> Fn for ExpOp<T> {
 
         (exp, dy * exp)
     }
+
+    // ln(f) = u, f'/f = u'
+    fn eval_log(self, input: f32) -> (f32, f32) {
+        self.expr.eval(input)
+    }
+}
+
+/// Constant base raised to an expression, `b^u`
+#[derive(Clone, Copy)]
+pub struct BasePowOp<T: Fn> {
+    expr: T,
+    base: f32,
+}
+
+impl<T: Fn> Fn for BasePowOp<T> {
+    // f(x) = b^u, f'(x) = u'b^u ln(b)
+    fn eval(self, input: f32) -> (f32, f32) {
+        let (y, dy) = self.expr.eval(input);
+        let pow = self.base.powf(y);
+
+        (pow, dy * pow * self.base.ln())
+    }
+
+    // ln(f) = u ln(b), f'/f = u'ln(b)
+    fn eval_log(self, input: f32) -> (f32, f32) {
+        let (y, dy) = self.expr.eval(input);
+        let ln = self.base.ln();
+
+        (y * ln, dy * ln)
+    }
+}
+
+/// Exponentation capped at `max_value`, with a zero derivative once capped
+#[derive(Clone, Copy)]
+pub struct ExpSaturatingOp<T: Fn> {
+    expr: T,
+    max_value: f32,
+}
+
+impl<T: Fn> Fn for ExpSaturatingOp<T> {
+    // f(x) = min(e^u, m), f'(x) = u'e^u below the cap and 0 above it
+    fn eval(self, input: f32) -> (f32, f32) {
+        let (y, dy) = self.expr.eval(input);
+        let exp = y.exp();
+
+        if exp >= self.max_value {
+            (self.max_value, 0.0)
+        } else {
+            (exp, dy * exp)
+        }
+    }
 }
 
 // Trigonometry
+
+#[cfg(test)]
+thread_local! {
+    static TRIG_EVALS: Cell<usize> = const { Cell::new(0) };
+}
+
+// f32::sin_cos, counted per thread in tests
+fn sin_cos(x: f32) -> (f32, f32) {
+    #[cfg(test)]
+    TRIG_EVALS.set(TRIG_EVALS.get() + 1);
+
+    x.sin_cos()
+}
+
 #[derive(Clone, Copy)]
 pub struct SinOp<T: Fn> {
     expr: T,
@@ -154,7 +396,7 @@ impl<T: Fn> Fn for SinOp<T> {
     // f(x) = sin(u), f'(x) = u'cos(u)
     fn eval(self, input: f32) -> (f32, f32) {
         let (y, dy) = self.expr.eval(input);
-        let (sin, cos) = y.sin_cos();
+        let (sin, cos) = sin_cos(y);
 
         (sin, dy * cos)
     }
@@ -169,7 +411,7 @@ impl<T: Fn> Fn for CosOp<T> {
     // f(x) = sin(u), f'(x) = u'cos(u)
     fn eval(self, input: f32) -> (f32, f32) {
         let (y, dy) = self.expr.eval(input);
-        let (sin, cos) = y.sin_cos();
+        let (sin, cos) = sin_cos(y);
 
         (cos, dy * -sin)
     }
@@ -191,6 +433,38 @@ impl<T: Fn> Fn for AtanOp<T> {
     }
 }
 
+/// Gudermannian function, relating circular and hyperbolic angles
+#[derive(Clone, Copy)]
+pub struct GdOp<T: Fn> {
+    expr: T,
+}
+
+impl<T: Fn> Fn for GdOp<T> {
+    // f(x) = 2atan(tanh(u/2)), f'(x) = u'/cosh(u)
+    fn eval(self, input: f32) -> (f32, f32) {
+        let (y, dy) = self.expr.eval(input);
+
+        (2.0 * (0.5 * y).tanh().atan(), dy / y.cosh())
+    }
+}
+
+/// Shortest signed angle from `target` to an expression, in `[-pi, pi]`
+#[derive(Clone, Copy)]
+pub struct AngleDiffOp<T: Fn> {
+    expr: T,
+    target: f32,
+}
+
+impl<T: Fn> Fn for AngleDiffOp<T> {
+    // f(x) = atan2(sin(u - t), cos(u - t)), f'(x) = u'
+    fn eval(self, input: f32) -> (f32, f32) {
+        let (y, dy) = self.expr.eval(input);
+        let (sin, cos) = (y - self.target).sin_cos();
+
+        (sin.atan2(cos), dy)
+    }
+}
+
 // Logarithm
 #[derive(Clone, Copy)]
 pub struct LnOp<T: Fn> {
@@ -206,268 +480,3074 @@ impl<T: Fn> Fn for LnOp<T> {
     }
 }
 
-// Composition of 2 functions
-pub struct ComposeOp<T1: Fn, T2: Fn> {
+/// Stable `ln(e^u + e^v)` of 2 expressions
+#[derive(Clone, Copy)]
+pub struct LogAddExpOp<T1: Fn, T2: Fn> {
     lhs: T1,
     rhs: T2,
 }
 
-impl<T1: Fn, T2: Fn> Fn for ComposeOp<T1, T2> {
-    // f(x) = g(h(x)), f'(x) = h'(x)g'(h(x))
+impl<T1: Fn, T2: Fn> Fn for LogAddExpOp<T1, T2> {
+    // f(x) = max(u, v) + ln(1 + e^-|u - v|)
+    // f'(x) = u'sigmoid(u - v) + v'sigmoid(v - u)
     fn eval(self, input: f32) -> (f32, f32) {
-        let (h, dh) = self.rhs.eval(input);
-        let (g, dg) = self.lhs.eval(h);
+        let (u, du) = self.lhs.eval(input);
+        let (v, dv) = self.rhs.eval(input);
 
-        (g, dh * dg)
+        let w = (-(u - v).abs()).exp();
+        let (max, dmax, dmin) = if u >= v { (u, du, dv) } else { (v, dv, du) };
+
+        (max + w.ln_1p(), (dmax + w * dmin) / (1.0 + w))
     }
 }
 
-/// The generic expression struct
+/// Two-class softmax probability `e^u/(e^u + e^v)`, i.e. `sigmoid(u - v)`
 #[derive(Clone, Copy)]
-pub struct Expr<T> {
-    expr: T,
+pub struct SoftmaxWithOp<T1: Fn, T2: Fn> {
+    lhs: T1,
+    rhs: T2,
 }
 
-impl<T: Fn> Fn for Expr<T> {
+impl<T1: Fn, T2: Fn> Fn for SoftmaxWithOp<T1, T2> {
+    // f(x) = sigmoid(u - v), f'(x) = f(1 - f)(u' - v')
     fn eval(self, input: f32) -> (f32, f32) {
-        self.expr.eval(input)
+        let (u, du) = self.lhs.eval(input);
+        let (v, dv) = self.rhs.eval(input);
+
+        // Only exponentiate the non-positive difference, so it can't overflow
+        let w = (-(u - v).abs()).exp();
+        let p = if u >= v {
+            1.0 / (1.0 + w)
+        } else {
+            w / (1.0 + w)
+        };
+
+        (p, p * (1.0 - p) * (du - dv))
     }
 }
 
-// Addition operator overloading
+/// Smooth minimum of 2 expressions, approaching `min(u, v)` as `k -> 0`
+///
+/// The smoothing width `k` must be positive.
+#[derive(Clone, Copy)]
+pub struct SminOp<T1: Fn, T2: Fn> {
+    lhs: T1,
+    rhs: T2,
+    k: f32,
+}
 
-impl<T1: Fn, T2: Fn> Add<Expr<T2>> for Expr<T1> {
-    type Output = Expr<AddOp<T1, T2>>;
+impl<T1: Fn, T2: Fn> Fn for SminOp<T1, T2> {
+    // f(x) = -k ln(e^(-u/k) + e^(-v/k))
+    // f'(x) = u'sigmoid((v - u)/k) + v'sigmoid((u - v)/k)
+    fn eval(self, input: f32) -> (f32, f32) {
+        let (u, du) = self.lhs.eval(input);
+        let (v, dv) = self.rhs.eval(input);
 
-    fn add(self, rhs: Expr<T2>) -> Self::Output {
-        Self::Output {
-            expr: AddOp {
-                lhs: self.expr,
-                rhs: rhs.expr,
-            },
-        }
+        let w = (-(u - v).abs() / self.k).exp();
+        let (min, dmin, dmax) = if u <= v { (u, du, dv) } else { (v, dv, du) };
+
+        (min - self.k * w.ln_1p(), (dmin + w * dmax) / (1.0 + w))
     }
 }
 
-impl<T: Fn> Add<f32> for Expr<T> {
-    type Output = Expr<AddOp<T, Const>>;
-
-    fn add(self, rhs: f32) -> Self::Output {
-        Self::Output {
-            expr: AddOp {
-                lhs: self.expr,
-                rhs: Const { value: rhs },
-            },
-        }
-    }
+/// Smooth maximum of 2 expressions, approaching `max(u, v)` as `k -> 0`
+///
+/// The smoothing width `k` must be positive.
+#[derive(Clone, Copy)]
+pub struct SmaxOp<T1: Fn, T2: Fn> {
+    lhs: T1,
+    rhs: T2,
+    k: f32,
 }
 
-impl<T: Fn> Add<Expr<T>> for f32 {
-    type Output = Expr<AddOp<Const, T>>;
+impl<T1: Fn, T2: Fn> Fn for SmaxOp<T1, T2> {
+    // f(x) = k ln(e^(u/k) + e^(v/k))
+    // f'(x) = u'sigmoid((u - v)/k) + v'sigmoid((v - u)/k)
+    fn eval(self, input: f32) -> (f32, f32) {
+        let (u, du) = self.lhs.eval(input);
+        let (v, dv) = self.rhs.eval(input);
 
-    fn add(self, rhs: Expr<T>) -> Self::Output {
-        Self::Output {
-            expr: AddOp {
-                lhs: Const { value: self },
-                rhs: rhs.expr,
-            },
-        }
+        let w = (-(u - v).abs() / self.k).exp();
+        let (max, dmax, dmin) = if u >= v { (u, du, dv) } else { (v, dv, du) };
+
+        (max + self.k * w.ln_1p(), (dmax + w * dmin) / (1.0 + w))
     }
 }
 
-// Multiplication operator overloading
+// Activations
+#[derive(Clone, Copy)]
+pub struct SoftsignOp<T: Fn> {
+    expr: T,
+}
 
-impl<T1: Fn, T2: Fn> Mul<Expr<T2>> for Expr<T1> {
-    type Output = Expr<MulOp<T1, T2>>;
+impl<T: Fn> Fn for SoftsignOp<T> {
+    // f(x) = u/(1 + |u|), f'(x) = u'/(1 + |u|)^2
+    fn eval(self, input: f32) -> (f32, f32) {
+        let (y, dy) = self.expr.eval(input);
+        let denom = 1.0 + y.abs();
 
-    fn mul(self, rhs: Expr<T2>) -> Self::Output {
-        Self::Output {
-            expr: MulOp {
-                lhs: self.expr,
-                rhs: rhs.expr,
-            },
-        }
+        (y / denom, dy / (denom * denom))
     }
 }
 
-impl<T: Fn> Mul<f32> for Expr<T> {
-    type Output = Expr<MulOp<T, Const>>;
-
-    fn mul(self, rhs: f32) -> Self::Output {
-        Self::Output {
-            expr: MulOp {
-                lhs: self.expr,
-                rhs: Const { value: rhs },
-            },
-        }
-    }
+#[derive(Clone, Copy)]
+pub struct MishOp<T: Fn> {
+    expr: T,
 }
 
-impl<T: Fn> Mul<Expr<T>> for f32 {
-    type Output = Expr<MulOp<Const, T>>;
+impl<T: Fn> Fn for MishOp<T> {
+    // f(x) = u tanh(softplus(u)), f'(x) = u'(t + u sigmoid(u)(1 - t^2)) with t = tanh(softplus(u))
+    fn eval(self, input: f32) -> (f32, f32) {
+        let (y, dy) = self.expr.eval(input);
+        let softplus = y.max(0.0) + (-y.abs()).exp().ln_1p();
+        let t = softplus.tanh();
+        let sigmoid = 1.0 / (1.0 + (-y).exp());
 
-    fn mul(self, rhs: Expr<T>) -> Self::Output {
-        Self::Output {
-            expr: MulOp {
-                lhs: Const { value: self },
-                rhs: rhs.expr,
-            },
-        }
+        (y * t, dy * (t + y * sigmoid * (1.0 - t * t)))
     }
 }
 
-// Substraction operator overloading
+/// Softsign-based approximation of the logistic sigmoid without `exp`
+///
+/// It stays within 0.083 of the true sigmoid on `[-5, 5]`.
+#[derive(Clone, Copy)]
+pub struct FastSigmoidOp<T: Fn> {
+    expr: T,
+}
 
-impl<T1: Fn, T2: Fn> Sub<Expr<T2>> for Expr<T1> {
-    type Output = Expr<SubOp<T1, T2>>;
+impl<T: Fn> Fn for FastSigmoidOp<T> {
+    // f(x) = 1/2 + u/(2(1 + |u|)), f'(x) = u'/(2(1 + |u|)^2)
+    fn eval(self, input: f32) -> (f32, f32) {
+        let (y, dy) = self.expr.eval(input);
+        let denom = 1.0 + y.abs();
+
+        (0.5 + 0.5 * y / denom, 0.5 * dy / (denom * denom))
+    }
+}
+
+/// Smoothly squashes an expression into `(lo, hi)` with an affine `tanh`
+///
+/// In `f32` the output saturates to exactly `lo` or `hi` once `|u|` exceeds
+/// about 9.
+#[derive(Clone, Copy)]
+pub struct SquashOp<T: Fn> {
+    expr: T,
+    lo: f32,
+    hi: f32,
+}
+
+impl<T: Fn> Fn for SquashOp<T> {
+    // f(x) = lo + (hi - lo)(tanh(u) + 1)/2, f'(x) = u'(hi - lo)(1 - tanh(u)^2)/2
+    fn eval(self, input: f32) -> (f32, f32) {
+        let (y, dy) = self.expr.eval(input);
+        let tanh = y.tanh();
+        let half_range = 0.5 * (self.hi - self.lo);
+
+        (
+            self.lo + half_range * (tanh + 1.0),
+            dy * half_range * (1.0 - tanh * tanh),
+        )
+    }
+}
+
+/// Piecewise-linear table through `(breaks[i], values[i])`, with the end
+/// segments extended past the first and last breakpoints
+#[derive(Clone, Copy)]
+pub struct PiecewiseLinearOp<'a, T: Fn> {
+    expr: T,
+    breaks: &'a [f32],
+    values: &'a [f32],
+}
+
+impl<T: Fn> Fn for PiecewiseLinearOp<'_, T> {
+    // f(x) = v_i + s_i(u - b_i), f'(x) = u's_i for the segment i containing u
+    fn eval(self, input: f32) -> (f32, f32) {
+        let (y, dy) = self.expr.eval(input);
+
+        let n = self.breaks.len();
+        let i = self.breaks.partition_point(|&b| b <= y).clamp(1, n - 1) - 1;
+        let slope = (self.values[i + 1] - self.values[i]) / (self.breaks[i + 1] - self.breaks[i]);
+
+        (self.values[i] + slope * (y - self.breaks[i]), dy * slope)
+    }
+}
+
+/// Randomly zeroes an expression with probability `p`, scaling it by
+/// `1/(1 - p)` otherwise so the expected value is unchanged
+///
+/// The mask is a hash of `seed` and the input only, so evaluating at the same
+/// point with the same seed always gives the same result. Use a different seed
+/// per step (or per sample) to draw a fresh mask, and a different seed per
+/// node for independent masks, since dropout nodes sharing a seed also share
+/// their mask.
+#[derive(Clone, Copy)]
+pub struct DropoutOp<T: Fn> {
+    expr: T,
+    p: f32,
+    seed: u64,
+}
+
+impl<T: Fn> Fn for DropoutOp<T> {
+    // f(x) = mu/(1 - p), f'(x) = mu'/(1 - p), with the mask m ~ Bernoulli(1 - p)
+    fn eval(self, input: f32) -> (f32, f32) {
+        let (y, dy) = self.expr.eval(input);
+
+        // Hash the seed first, so that it can't cancel out the input bits
+        let stream = Rng::new(self.seed).next_u64();
+        let mut rng = Rng::new(stream ^ u64::from(input.to_bits()));
+
+        if rng.next_f32() < self.p {
+            (0.0, 0.0)
+        } else {
+            let scale = 1.0 / (1.0 - self.p);
+
+            (y * scale, dy * scale)
+        }
+    }
+}
+
+// Losses
+#[derive(Clone, Copy)]
+pub struct PseudoHuberOp<T: Fn> {
+    expr: T,
+    delta: f32,
+}
+
+impl<T: Fn> Fn for PseudoHuberOp<T> {
+    // f(x) = d^2(sqrt(1 + (u/d)^2) - 1), f'(x) = u'u/sqrt(1 + (u/d)^2)
+    fn eval(self, input: f32) -> (f32, f32) {
+        let (y, dy) = self.expr.eval(input);
+        let scaled = y / self.delta;
+        let root = (1.0 + scaled * scaled).sqrt();
+
+        (self.delta * self.delta * (root - 1.0), dy * y / root)
+    }
+}
+
+// Domain guards
+
+/// Smoothly maps an expression onto `(eps, inf)` with `softplus(u) + eps`
+///
+/// Where the softplus is too small to change `eps` in f32, e.g. for very
+/// negative `u`, the value is the next float above `eps` instead.
+#[derive(Clone, Copy)]
+pub struct EnsurePositiveOp<T: Fn> {
+    expr: T,
+    eps: f32,
+}
+
+impl<T: Fn> Fn for EnsurePositiveOp<T> {
+    // f(x) = ln(1 + e^u) + eps, f'(x) = u'/(1 + e^-u)
+    fn eval(self, input: f32) -> (f32, f32) {
+        let (y, dy) = self.expr.eval(input);
+        let softplus = y.max(0.0) + (-y.abs()).exp().ln_1p();
+        let value = softplus + self.eps;
+        let value = if value <= self.eps {
+            self.eps.next_up()
+        } else {
+            value
+        };
+
+        (value, dy / (1.0 + (-y).exp()))
+    }
+}
+
+/// Square root softened by `eps`, keeping the derivative finite at 0
+#[derive(Clone, Copy)]
+pub struct SqrtSafeOp<T: Fn> {
+    expr: T,
+    eps: f32,
+}
+
+impl<T: Fn> Fn for SqrtSafeOp<T> {
+    // f(x) = sqrt(u + eps), f'(x) = u'/(2sqrt(u + eps))
+    fn eval(self, input: f32) -> (f32, f32) {
+        let (y, dy) = self.expr.eval(input);
+        let sqrt = (y + self.eps).sqrt();
+
+        (sqrt, dy / (2.0 * sqrt))
+    }
+}
+
+/// Replaces a NaN or infinite value or derivative of an expression with 0
+#[derive(Clone, Copy)]
+pub struct SanitizeOp<T: Fn> {
+    expr: T,
+}
+
+impl<T: Fn> Fn for SanitizeOp<T> {
+    fn eval(self, input: f32) -> (f32, f32) {
+        let (y, dy) = self.expr.eval(input);
+        let finite_or_zero = |v: f32| if v.is_finite() { v } else { 0.0 };
+
+        (finite_or_zero(y), finite_or_zero(dy))
+    }
+
+    // A value that overflows stays finite in log space, only the log-derivative
+    // needs sanitizing
+    fn eval_log(self, input: f32) -> (f32, f32) {
+        let (y, dy) = self.expr.eval_log(input);
+
+        (y, if dy.is_finite() { dy } else { 0.0 })
+    }
+}
+
+// Gradient manipulation
+
+/// Keeps the value of an expression but replaces its local derivative
+#[derive(Clone, Copy)]
+pub struct OverrideGradOp<T: Fn, F: std::ops::Fn(f32) -> f32> {
+    expr: T,
+    surrogate: F,
+}
+
+impl<T: Fn, F: std::ops::Fn(f32) -> f32> Fn for OverrideGradOp<T, F> {
+    // f(x) = u, f'(x) = u'g(u) for a surrogate gradient g
+    fn eval(self, input: f32) -> (f32, f32) {
+        let (y, dy) = self.expr.eval(input);
+
+        (y, dy * (self.surrogate)(y))
+    }
+}
+
+/// Masks out negative and/or positive derivatives of an expression
+#[derive(Clone, Copy)]
+pub struct ClampDerivOp<T: Fn> {
+    expr: T,
+    allow_negative: bool,
+    allow_positive: bool,
+}
+
+impl<T: Fn> Fn for ClampDerivOp<T> {
+    // f(x) = u, f'(x) = u' if its sign is allowed, otherwise 0
+    fn eval(self, input: f32) -> (f32, f32) {
+        let (y, dy) = self.expr.eval(input);
+
+        let allowed = (dy < 0.0 && self.allow_negative) || (dy > 0.0 && self.allow_positive);
+
+        (y, if allowed { dy } else { 0.0 })
+    }
+}
+
+/// Rounds an expression to a multiple of `step` with a straight-through
+/// estimator: the derivative ignores the rounding
+#[derive(Clone, Copy)]
+pub struct RoundToSteOp<T: Fn> {
+    expr: T,
+    step: f32,
+}
+
+impl<T: Fn> Fn for RoundToSteOp<T> {
+    // f(x) = round(u/s)s, f'(x) = u'
+    fn eval(self, input: f32) -> (f32, f32) {
+        let (y, dy) = self.expr.eval(input);
+
+        ((y / self.step).round() * self.step, dy)
+    }
+}
+
+/// Evaluates an expression at the input snapped to a multiple of `step`,
+/// passing the derivative straight through the snapping
+#[derive(Clone, Copy)]
+pub struct SnapInputOp<T: Fn> {
+    expr: T,
+    step: f32,
+}
+
+impl<T: Fn> Fn for SnapInputOp<T> {
+    // f(x) = u(round(x/s)s), f'(x) = u'(round(x/s)s)
+    fn eval(self, input: f32) -> (f32, f32) {
+        self.expr.eval((input / self.step).round() * self.step)
+    }
+}
+
+/// Scales the derivative of an expression, leaving its value unchanged
+#[derive(Clone, Copy)]
+pub struct ScaleGradOp<T: Fn> {
+    expr: T,
+    factor: f32,
+}
+
+impl<T: Fn> Fn for ScaleGradOp<T> {
+    // f(x) = u, f'(x) = ku'
+    fn eval(self, input: f32) -> (f32, f32) {
+        let (y, dy) = self.expr.eval(input);
+
+        (y, dy * self.factor)
+    }
+
+    // ln(f) = ln(u), f'/f = ku'/u
+    fn eval_log(self, input: f32) -> (f32, f32) {
+        let (y, dy) = self.expr.eval_log(input);
+
+        (y, dy * self.factor)
+    }
+}
+
+/// Replaces the derivative of an expression with its sign
+#[derive(Clone, Copy)]
+pub struct GradSignOp<T: Fn> {
+    expr: T,
+}
+
+impl<T: Fn> Fn for GradSignOp<T> {
+    // f(x) = u, f'(x) = sign(u')
+    fn eval(self, input: f32) -> (f32, f32) {
+        let (y, dy) = self.expr.eval(input);
+
+        (y, if dy == 0.0 { 0.0 } else { dy.signum() })
+    }
+}
+
+/// Falls back to a central finite difference with step `h` when the analytic
+/// derivative of an expression is NaN or infinite
+#[derive(Clone, Copy)]
+pub struct RobustDerivOp<T: Fn> {
+    expr: T,
+    h: f32,
+}
+
+impl<T: Fn + Copy> Fn for RobustDerivOp<T> {
+    // f(x) = u, f'(x) = u' or (u(x + h) - u(x - h))/2h if u' is not finite
+    fn eval(self, input: f32) -> (f32, f32) {
+        let (y, dy) = self.expr.eval(input);
+
+        if dy.is_finite() {
+            return (y, dy);
+        }
+
+        let (y1, _) = self.expr.eval(input + self.h);
+        let (y0, _) = self.expr.eval(input - self.h);
+
+        (y, (y1 - y0) / (2.0 * self.h))
+    }
+}
+
+// Memoization
+
+const MEMO_SLOTS: usize = 64;
+
+static NEXT_MEMO_ID: AtomicUsize = AtomicUsize::new(0);
+
+// Latest (node id, generation, input bits, result) of a slot, node ids map to
+// slot id % MEMO_SLOTS
+type MemoTable<R> = RefCell<[Option<(usize, u64, u32, R)>; MEMO_SLOTS]>;
+
+thread_local! {
+    static MEMO: MemoTable<(f32, f32)> = const { RefCell::new([None; MEMO_SLOTS]) };
+
+    // (sin(u), cos(u), u') of `sincos` nodes
+    static SINCOS: MemoTable<(f32, f32, f32)> = const { RefCell::new([None; MEMO_SLOTS]) };
+
+    // Bumped whenever a memo scope is evaluated, older entries are stale
+    static MEMO_GENERATION: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Shares the evaluation of an expression between all copies of the node
+///
+/// Results are cached per thread in a small fixed-size scratch table keyed by
+/// node and input, so an expression that uses a memoized node several times
+/// evaluates it once per input.
+///
+/// Wrap the whole expression in `memo_scope` to forget cached results at the
+/// start of each evaluation. Outside of a scope a result stays cached across
+/// evaluations, so the memoized expression must depend on its input only,
+/// e.g. an `override_grad` surrogate reading state that changes between
+/// evaluations would keep its old result.
+///
+/// A lookup costs about as much as a few arithmetic ops. When the whole
+/// expression is inlined the compiler can often share a pure sub-expression on
+/// its own, so memoizing pays off for expensive nodes it can't see through,
+/// like a `compose_chain` (see `benches/memoize.rs`).
+#[derive(Clone, Copy)]
+pub struct MemoOp<T: Fn> {
+    expr: T,
+    id: usize,
+}
+
+// Look up the result of node `id` at `input` in `table`, computing and storing
+// it on a miss
+fn memoized<R: Copy>(
+    table: &'static LocalKey<MemoTable<R>>,
+    id: usize,
+    input: f32,
+    compute: impl FnOnce() -> R,
+) -> R {
+    let slot = id % MEMO_SLOTS;
+    let key = (id, MEMO_GENERATION.get(), input.to_bits());
+
+    let cached = table.with_borrow(|memo| match memo[slot] {
+        Some((id, generation, bits, result)) if (id, generation, bits) == key => Some(result),
+        _ => None,
+    });
+
+    if let Some(result) = cached {
+        return result;
+    }
+
+    // The borrow is released while computing, memoized nodes may be nested
+    let result = compute();
+    table.with_borrow_mut(|memo| memo[slot] = Some((key.0, key.1, key.2, result)));
+
+    result
+}
+
+impl<T: Fn> Fn for MemoOp<T> {
+    fn eval(self, input: f32) -> (f32, f32) {
+        memoized(&MEMO, self.id, input, || self.expr.eval(input))
+    }
+
+    // Log-space results aren't cached, they would need slots of their own
+    fn eval_log(self, input: f32) -> (f32, f32) {
+        self.expr.eval_log(input)
+    }
+}
+
+/// Evaluates an expression in a fresh memoization scope, so the memoized
+/// nodes inside it are evaluated at most once per evaluation of the scope
+#[derive(Clone, Copy)]
+pub struct MemoScopeOp<T: Fn> {
+    expr: T,
+}
+
+impl<T: Fn> Fn for MemoScopeOp<T> {
+    fn eval(self, input: f32) -> (f32, f32) {
+        MEMO_GENERATION.set(MEMO_GENERATION.get() + 1);
+
+        self.expr.eval(input)
+    }
+
+    fn eval_log(self, input: f32) -> (f32, f32) {
+        MEMO_GENERATION.set(MEMO_GENERATION.get() + 1);
+
+        self.expr.eval_log(input)
+    }
+}
+
+/// The sine or cosine half of `sincos`
+///
+/// Both halves share one cached evaluation of the argument and its
+/// `sin_cos`, with the same lifetime as a `memoize` result.
+#[derive(Clone, Copy)]
+pub struct SinCosOp<T: Fn> {
+    expr: T,
+    id: usize,
+    cos: bool,
+}
+
+impl<T: Fn> Fn for SinCosOp<T> {
+    // sin: f(x) = sin(u), f'(x) = u'cos(u)
+    // cos: f(x) = cos(u), f'(x) = -u'sin(u)
+    fn eval(self, input: f32) -> (f32, f32) {
+        let (sin, cos, dy) = memoized(&SINCOS, self.id, input, || {
+            let (y, dy) = self.expr.eval(input);
+            let (sin, cos) = sin_cos(y);
+
+            (sin, cos, dy)
+        });
+
+        if self.cos {
+            (cos, dy * -sin)
+        } else {
+            (sin, dy * cos)
+        }
+    }
+}
+
+// Composition of 2 functions
+#[derive(Clone, Copy)]
+pub struct ComposeOp<T1: Fn, T2: Fn> {
+    lhs: T1,
+    rhs: T2,
+}
+
+impl<T1: Fn, T2: Fn> Fn for ComposeOp<T1, T2> {
+    // f(x) = g(h(x)), f'(x) = h'(x)g'(h(x))
+    fn eval(self, input: f32) -> (f32, f32) {
+        let (h, dh) = self.rhs.eval(input);
+        let (g, dg) = self.lhs.eval(h);
+
+        (g, dh * dg)
+    }
+
+    // ln(f) = ln(g(h(x))), f'/f = h'(x)g'(h(x))/g(h(x))
+    fn eval_log(self, input: f32) -> (f32, f32) {
+        let (h, dh) = self.rhs.eval(input);
+        let (g, dg) = self.lhs.eval_log(h);
+
+        (g, dh * dg)
+    }
+}
+
+/// Composition of a chain of expressions, evaluated iteratively
+#[derive(Clone, Copy)]
+pub struct ComposeChainOp<'a, T: Fn> {
+    exprs: &'a [Expr<T>],
+}
+
+impl<T: Fn + Copy> Fn for ComposeChainOp<'_, T> {
+    // f(x) = g_0(g_1(...g_n(x))), f'(x) = product of g_i' along the chain
+    fn eval(self, input: f32) -> (f32, f32) {
+        let mut value = input;
+        let mut derivative = 1.0;
+
+        for expr in self.exprs.iter().rev() {
+            let (y, dy) = expr.eval(value);
+
+            value = y;
+            derivative *= dy;
+        }
+
+        (value, derivative)
+    }
+}
+
+/// Periodic extension of an expression defined on `[0, period)`
+///
+/// The derivative jumps at multiples of `period` unless the expression's
+/// value and derivative match at both ends of the period.
+#[derive(Clone, Copy)]
+pub struct PeriodicOp<T: Fn> {
+    expr: T,
+    period: f32,
+}
+
+impl<T: Fn> Fn for PeriodicOp<T> {
+    // f(x) = g(x mod p), f'(x) = g'(x mod p)
+    fn eval(self, input: f32) -> (f32, f32) {
+        self.expr.eval(input.rem_euclid(self.period))
+    }
+}
+
+/// Polynomial bases supported by `from_basis`
+#[derive(Clone, Copy)]
+pub enum Basis {
+    Monomial,
+    Chebyshev,
+    Legendre,
+}
+
+// Series in a polynomial basis
+#[derive(Clone, Copy)]
+pub struct BasisOp<'a> {
+    basis: Basis,
+    coeffs: &'a [f32],
+}
+
+impl Fn for BasisOp<'_> {
+    // f(x) = sum(c_k * p_k(x)), f'(x) = sum(c_k * p_k'(x))
+    fn eval(self, input: f32) -> (f32, f32) {
+        let x = input;
+
+        match self.basis {
+            Basis::Monomial => {
+                // Horner's scheme, carrying the derivative along
+                let mut value = 0.0;
+                let mut derivative = 0.0;
+
+                for &c in self.coeffs.iter().rev() {
+                    derivative = derivative * x + value;
+                    value = value * x + c;
+                }
+
+                (value, derivative)
+            }
+            // T_(n+1) = 2xT_n - T_(n-1)
+            Basis::Chebyshev => recurrence_series(self.coeffs, x, |_, (p0, dp0), (p1, dp1)| {
+                (2.0 * x * p1 - p0, 2.0 * (p1 + x * dp1) - dp0)
+            }),
+            // (n + 1)P_(n+1) = (2n + 1)xP_n - nP_(n-1)
+            Basis::Legendre => recurrence_series(self.coeffs, x, |n, (p0, dp0), (p1, dp1)| {
+                (
+                    ((2.0 * n + 1.0) * x * p1 - n * p0) / (n + 1.0),
+                    ((2.0 * n + 1.0) * (p1 + x * dp1) - n * dp0) / (n + 1.0),
+                )
+            }),
+        }
+    }
+}
+
+// Sum c_k * p_k(x) for polynomials starting at p_0 = 1, p_1 = x, where
+// next(n, p_(n-1), p_n) gives p_(n+1), each paired with its derivative
+fn recurrence_series(
+    coeffs: &[f32],
+    x: f32,
+    next: impl std::ops::Fn(f32, (f32, f32), (f32, f32)) -> (f32, f32),
+) -> (f32, f32) {
+    let mut value = 0.0;
+    let mut derivative = 0.0;
+
+    let mut p0 = (1.0, 0.0);
+    let mut p1 = (x, 1.0);
+
+    for (k, &c) in coeffs.iter().enumerate() {
+        value += c * p0.0;
+        derivative += c * p0.1;
+
+        (p0, p1) = (p1, next((k + 1) as f32, p0, p1));
+    }
+
+    (value, derivative)
+}
+
+/// Product of a sequence of expressions, multiplied as a balanced tree
+#[derive(Clone, Copy)]
+pub struct BalancedProductOp<'a, T: Fn> {
+    terms: &'a [Expr<T>],
+}
+
+impl<T: Fn + Copy> Fn for BalancedProductOp<'_, T> {
+    // f(x) = uv, f'(x) = uv' + vu' for the products u, v of each half
+    fn eval(self, input: f32) -> (f32, f32) {
+        match self.terms {
+            [] => (1.0, 0.0),
+            [term] => term.eval(input),
+            terms => {
+                let (lhs, rhs) = terms.split_at(terms.len() / 2);
+                let (u, du) = BalancedProductOp { terms: lhs }.eval(input);
+                let (v, dv) = BalancedProductOp { terms: rhs }.eval(input);
+
+                (u * v, u * dv + v * du)
+            }
+        }
+    }
+
+    // ln(f) = sum(ln(u_i)), f'/f = sum(u_i'/u_i)
+    fn eval_log(self, input: f32) -> (f32, f32) {
+        self.terms.iter().fold((0.0, 0.0), |(log, dlog), term| {
+            let (y, dy) = term.eval_log(input);
+
+            (log + y, dlog + dy)
+        })
+    }
+}
+
+/// Piecewise expression, switching segments at sorted breakpoints
+#[derive(Clone, Copy)]
+pub struct PiecewiseOp<'a, T: Fn> {
+    breaks: &'a [f32],
+    segments: &'a [Expr<T>],
+}
+
+impl<T: Fn + Copy> Fn for PiecewiseOp<'_, T> {
+    fn eval(self, input: f32) -> (f32, f32) {
+        let i = self.breaks.partition_point(|&b| b <= input);
+
+        self.segments[i].eval(input)
+    }
+}
+
+/// Total variation of a sequence of expressions
+#[derive(Clone, Copy)]
+pub struct TotalVariationOp<'a, T: Fn> {
+    exprs: &'a [Expr<T>],
+}
+
+impl<T: Fn + Copy> Fn for TotalVariationOp<'_, T> {
+    // f(x) = sum(|u_(i+1) - u_i|), f'(x) = sum(sign(u_(i+1) - u_i)(u_(i+1)' - u_i'))
+    // using the subgradient 0 where consecutive values are equal
+    fn eval(self, input: f32) -> (f32, f32) {
+        let mut value = 0.0;
+        let mut derivative = 0.0;
+
+        let mut values = self.exprs.iter().map(|expr| expr.eval(input));
+
+        if let Some(mut prev) = values.next() {
+            for (y, dy) in values {
+                let diff = y - prev.0;
+                let sign = if diff > 0.0 {
+                    1.0
+                } else if diff < 0.0 {
+                    -1.0
+                } else {
+                    0.0
+                };
+
+                value += diff.abs();
+                derivative += sign * (dy - prev.1);
+                prev = (y, dy);
+            }
+        }
+
+        (value, derivative)
+    }
+}
+
+/// 1D Bezier curve in the parameter `t`
+#[derive(Clone, Copy)]
+pub struct BezierOp<'a> {
+    points: &'a [f32],
+}
+
+impl Fn for BezierOp<'_> {
+    // f(t) = sum(P_i * b_(i,n)(t)), f'(t) = n * sum((P_(i+1) - P_i) * b_(i,n-1)(t))
+    fn eval(self, input: f32) -> (f32, f32) {
+        let t = input;
+        let points = self.points;
+
+        let n = match points.len() {
+            0 => return (0.0, 0.0),
+            1 => return (points[0], 0.0),
+            len => len - 1,
+        };
+
+        (
+            bernstein_sum(n, |i| points[i], t),
+            n as f32 * bernstein_sum(n - 1, |i| points[i + 1] - points[i], t),
+        )
+    }
+}
+
+// Sum c_i * C(n, i) t^i (1 - t)^(n - i) by Horner's scheme in t/(1 - t), or in
+// (1 - t)/t from the other end for t > 1/2 so the ratio stays small
+fn bernstein_sum(n: usize, c: impl std::ops::Fn(usize) -> f32, t: f32) -> f32 {
+    let (ratio, scale) = if t <= 0.5 {
+        (t / (1.0 - t), 1.0 - t)
+    } else {
+        ((1.0 - t) / t, t)
+    };
+    let coeff = |i: usize| if t <= 0.5 { c(i) } else { c(n - i) };
+
+    let mut sum = coeff(n);
+    let mut binomial = 1.0;
+
+    for i in (0..n).rev() {
+        // C(n, i) = C(n, i + 1)(i + 1)/(n - i)
+        binomial *= (i + 1) as f32 / (n - i) as f32;
+        sum = sum * ratio + binomial * coeff(i);
+    }
+
+    sum * scale.powi(n as i32)
+}
+
+/// Natural cubic spline interpolating sorted `(x, y)` knots
+pub struct CubicSpline {
+    xs: Vec<f32>,
+    ys: Vec<f32>,
+    // Second derivative of the interpolant at each knot
+    m: Vec<f32>,
+}
+
+impl CubicSpline {
+    /// Precompute the spline through `knots`, which must be sorted by `x`
+    ///
+    /// Panics if there are fewer than 2 knots.
+    pub fn new(knots: &[(f32, f32)]) -> Self {
+        assert!(knots.len() >= 2, "a spline needs at least 2 knots");
+
+        let (xs, ys): (Vec<f32>, Vec<f32>) = knots.iter().copied().unzip();
+        let n = xs.len();
+        let mut m = vec![0.0; n];
+
+        // Solve the tridiagonal system for the interior second derivatives
+        // with the Thomas algorithm, the natural boundary fixes m[0] = m[n - 1] = 0
+        let mut c = vec![0.0; n];
+        let mut d = vec![0.0; n];
+
+        for i in 1..n - 1 {
+            let h0 = xs[i] - xs[i - 1];
+            let h1 = xs[i + 1] - xs[i];
+            let rhs = 6.0 * ((ys[i + 1] - ys[i]) / h1 - (ys[i] - ys[i - 1]) / h0);
+            let diag = 2.0 * (h0 + h1) - h0 * c[i - 1];
+
+            c[i] = h1 / diag;
+            d[i] = (rhs - h0 * d[i - 1]) / diag;
+        }
+
+        for i in (1..n - 1).rev() {
+            m[i] = d[i] - c[i] * m[i + 1];
+        }
+
+        Self { xs, ys, m }
+    }
+
+    pub fn expr(&self) -> Expr<&Self> {
+        Expr { expr: self }
+    }
+}
+
+impl Fn for &CubicSpline {
+    // Inputs outside the knots extend the first or last cubic segment
+    fn eval(self, input: f32) -> (f32, f32) {
+        let n = self.xs.len();
+        let i = self.xs.partition_point(|&x| x <= input).clamp(1, n - 1) - 1;
+
+        let h = self.xs[i + 1] - self.xs[i];
+        let a = self.xs[i + 1] - input;
+        let b = input - self.xs[i];
+
+        let (m0, m1) = (self.m[i], self.m[i + 1]);
+        let c0 = self.ys[i] / h - m0 * h / 6.0;
+        let c1 = self.ys[i + 1] / h - m1 * h / 6.0;
+
+        (
+            (m0 * a * a * a + m1 * b * b * b) / (6.0 * h) + c0 * a + c1 * b,
+            (m1 * b * b - m0 * a * a) / (2.0 * h) + c1 - c0,
+        )
+    }
+}
+
+/// Uniformly sampled lookup table evaluated by linear interpolation, as
+/// returned by `tabulate`
+///
+/// With sample spacing `h`, the interpolated value is within
+/// `h^2/8 * max|f''|` of the tabulated function on `[lo, hi]`, up to f32
+/// rounding once the bound gets that small.
+pub struct TableFn {
+    lo: f32,
+    step: f32,
+    values: Vec<f32>,
+}
+
+impl TableFn {
+    pub fn expr(&self) -> Expr<&Self> {
+        Expr { expr: self }
+    }
+}
+
+impl Fn for &TableFn {
+    // Inputs outside the table extend the first or last segment
+    fn eval(self, input: f32) -> (f32, f32) {
+        let t = (input - self.lo) / self.step;
+        let i = (t.floor().max(0.0) as usize).min(self.values.len() - 2);
+        let (y0, y1) = (self.values[i], self.values[i + 1]);
+
+        (y0 + (y1 - y0) * (t - i as f32), (y1 - y0) / self.step)
+    }
+}
+
+/// Why a root finder stopped without reaching its tolerance, along with its
+/// last estimate and the residual `f(estimate)` there
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SolveError {
+    ZeroDerivative { estimate: f32, residual: f32 },
+    NotConverged { estimate: f32, residual: f32 },
+}
+
+impl std::fmt::Display for SolveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SolveError::ZeroDerivative { estimate, residual } => {
+                write!(f, "zero derivative at x = {estimate} (residual {residual})")
+            }
+            SolveError::NotConverged { estimate, residual } => write!(
+                f,
+                "did not converge, last x = {estimate} (residual {residual})"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SolveError {}
+
+/// The name passed to `activation` is not a known activation
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnknownActivation(pub String);
+
+impl std::fmt::Display for UnknownActivation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "unknown activation {:?}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownActivation {}
+
+/// The affine function `ax + b`
+pub type Affine = AddOp<MulOp<Const, Var>, Const>;
+
+/// An activation as a plain function, as returned by `activation`
+pub type Activation = fn(f32) -> (f32, f32);
+
+/// The sine and cosine of a shared argument, as returned by `sincos`
+pub type SinCos<T> = (Expr<SinCosOp<T>>, Expr<SinCosOp<T>>);
+
+/// The generic expression struct
+#[derive(Clone, Copy)]
+pub struct Expr<T> {
+    expr: T,
+}
+
+impl<T: Fn> Fn for Expr<T> {
+    fn eval(self, input: f32) -> (f32, f32) {
+        self.expr.eval(input)
+    }
+
+    fn eval_log(self, input: f32) -> (f32, f32) {
+        self.expr.eval_log(input)
+    }
+}
+
+// Addition operator overloading
+
+impl<T1: Fn, T2: Fn> Add<Expr<T2>> for Expr<T1> {
+    type Output = Expr<AddOp<T1, T2>>;
+
+    fn add(self, rhs: Expr<T2>) -> Self::Output {
+        Self::Output {
+            expr: AddOp {
+                lhs: self.expr,
+                rhs: rhs.expr,
+            },
+        }
+    }
+}
+
+impl<T: Fn> Add<f32> for Expr<T> {
+    type Output = Expr<AddOp<T, Const>>;
+
+    fn add(self, rhs: f32) -> Self::Output {
+        Self::Output {
+            expr: AddOp {
+                lhs: self.expr,
+                rhs: Const { value: rhs },
+            },
+        }
+    }
+}
+
+impl<T: Fn> Add<Expr<T>> for f32 {
+    type Output = Expr<AddOp<Const, T>>;
+
+    fn add(self, rhs: Expr<T>) -> Self::Output {
+        Self::Output {
+            expr: AddOp {
+                lhs: Const { value: self },
+                rhs: rhs.expr,
+            },
+        }
+    }
+}
+
+// Multiplication operator overloading
+
+impl<T1: Fn, T2: Fn> Mul<Expr<T2>> for Expr<T1> {
+    type Output = Expr<MulOp<T1, T2>>;
+
+    fn mul(self, rhs: Expr<T2>) -> Self::Output {
+        Self::Output {
+            expr: MulOp {
+                lhs: self.expr,
+                rhs: rhs.expr,
+            },
+        }
+    }
+}
+
+impl<T: Fn> Mul<f32> for Expr<T> {
+    type Output = Expr<MulOp<T, Const>>;
+
+    fn mul(self, rhs: f32) -> Self::Output {
+        Self::Output {
+            expr: MulOp {
+                lhs: self.expr,
+                rhs: Const { value: rhs },
+            },
+        }
+    }
+}
+
+impl<T: Fn> Mul<Expr<T>> for f32 {
+    type Output = Expr<MulOp<Const, T>>;
+
+    fn mul(self, rhs: Expr<T>) -> Self::Output {
+        Self::Output {
+            expr: MulOp {
+                lhs: Const { value: self },
+                rhs: rhs.expr,
+            },
+        }
+    }
+}
+
+// Substraction operator overloading
+
+impl<T1: Fn, T2: Fn> Sub<Expr<T2>> for Expr<T1> {
+    type Output = Expr<SubOp<T1, T2>>;
 
     fn sub(self, rhs: Expr<T2>) -> Self::Output {
         Self::Output {
-            expr: SubOp {
+            expr: SubOp {
+                lhs: self.expr,
+                rhs: rhs.expr,
+            },
+        }
+    }
+}
+
+impl<T: Fn> Sub<f32> for Expr<T> {
+    type Output = Expr<SubOp<T, Const>>;
+
+    fn sub(self, rhs: f32) -> Self::Output {
+        Self::Output {
+            expr: SubOp {
+                lhs: self.expr,
+                rhs: Const { value: rhs },
+            },
+        }
+    }
+}
+
+impl<T: Fn> Sub<Expr<T>> for f32 {
+    type Output = Expr<SubOp<Const, T>>;
+
+    fn sub(self, rhs: Expr<T>) -> Self::Output {
+        Self::Output {
+            expr: SubOp {
+                lhs: Const { value: self },
+                rhs: rhs.expr,
+            },
+        }
+    }
+}
+
+// Negation operator overloading
+
+impl<T: Fn> Neg for Expr<T> {
+    type Output = Expr<NegOp<T>>;
+
+    fn neg(self) -> Self::Output {
+        Self::Output {
+            expr: NegOp { expr: self.expr },
+        }
+    }
+}
+
+// Division operator overloading
+
+impl<T1: Fn, T2: Fn> Div<Expr<T2>> for Expr<T1> {
+    type Output = Expr<DivOp<T1, T2>>;
+
+    fn div(self, rhs: Expr<T2>) -> Self::Output {
+        Self::Output {
+            expr: DivOp {
+                lhs: self.expr,
+                rhs: rhs.expr,
+            },
+        }
+    }
+}
+
+impl<T: Fn> Div<f32> for Expr<T> {
+    type Output = Expr<DivOp<T, Const>>;
+
+    fn div(self, rhs: f32) -> Self::Output {
+        Self::Output {
+            expr: DivOp {
+                lhs: self.expr,
+                rhs: Const { value: rhs },
+            },
+        }
+    }
+}
+
+impl<T: Fn> Div<Expr<T>> for f32 {
+    type Output = Expr<DivOp<Const, T>>;
+
+    fn div(self, rhs: Expr<T>) -> Self::Output {
+        Self::Output {
+            expr: DivOp {
+                lhs: Const { value: self },
+                rhs: rhs.expr,
+            },
+        }
+    }
+}
+
+// Remainder operator overloading
+
+impl<T: Fn> Rem<f32> for Expr<T> {
+    type Output = Expr<RemOp<T>>;
+
+    fn rem(self, rhs: f32) -> Self::Output {
+        Self::Output {
+            expr: RemOp {
+                expr: self.expr,
+                period: rhs,
+            },
+        }
+    }
+}
+
+impl<T: Fn> Expr<T> {
+    pub fn floor_div(self, divisor: f32) -> Expr<FloorDivOp<T>> {
+        Expr {
+            expr: FloorDivOp {
+                expr: self.expr,
+                divisor,
+            },
+        }
+    }
+
+    pub fn pow(self, order: f32) -> Expr<PowOp<T>> {
+        Expr {
+            expr: PowOp {
+                expr: self.expr,
+                order,
+            },
+        }
+    }
+
+    pub fn sqrt(self) -> Expr<PowOp<T>> {
+        Expr {
+            expr: PowOp {
+                expr: self.expr,
+                order: 0.5,
+            },
+        }
+    }
+
+    pub fn pow_clamped(self, order: f32, floor: f32) -> Expr<PowClampedOp<T>> {
+        Expr {
+            expr: PowClampedOp {
+                expr: self.expr,
+                order,
+                floor,
+            },
+        }
+    }
+
+    pub fn signed_pow(self, order: f32) -> Expr<SignedPowOp<T>> {
+        Expr {
+            expr: SignedPowOp {
+                expr: self.expr,
+                order,
+            },
+        }
+    }
+
+    pub fn pow_half(self, numerator: i32) -> Expr<PowHalfOp<T>> {
+        Expr {
+            expr: PowHalfOp {
+                expr: self.expr,
+                numerator,
+            },
+        }
+    }
+
+    pub fn exp(self) -> Expr<ExpOp<T>> {
+        Expr {
+            expr: ExpOp { expr: self.expr },
+        }
+    }
+
+    pub fn exp_saturating(self, max_value: f32) -> Expr<ExpSaturatingOp<T>> {
+        Expr {
+            expr: ExpSaturatingOp {
+                expr: self.expr,
+                max_value,
+            },
+        }
+    }
+
+    pub fn sin(self) -> Expr<SinOp<T>> {
+        Expr {
+            expr: SinOp { expr: self.expr },
+        }
+    }
+
+    pub fn cos(self) -> Expr<CosOp<T>> {
+        Expr {
+            expr: CosOp { expr: self.expr },
+        }
+    }
+
+    pub fn atan(self) -> Expr<AtanOp<T>> {
+        Expr {
+            expr: AtanOp { expr: self.expr },
+        }
+    }
+
+    pub fn gd(self) -> Expr<GdOp<T>> {
+        Expr {
+            expr: GdOp { expr: self.expr },
+        }
+    }
+
+    pub fn angle_diff(self, target: f32) -> Expr<AngleDiffOp<T>> {
+        Expr {
+            expr: AngleDiffOp {
+                expr: self.expr,
+                target,
+            },
+        }
+    }
+
+    pub fn ln(self) -> Expr<LnOp<T>> {
+        Expr {
+            expr: LnOp { expr: self.expr },
+        }
+    }
+
+    pub fn logaddexp<T1: Fn>(self, other: Expr<T1>) -> Expr<LogAddExpOp<T, T1>> {
+        Expr {
+            expr: LogAddExpOp {
+                lhs: self.expr,
+                rhs: other.expr,
+            },
+        }
+    }
+
+    pub fn softmax_with<T1: Fn>(self, other: Expr<T1>) -> Expr<SoftmaxWithOp<T, T1>> {
+        Expr {
+            expr: SoftmaxWithOp {
+                lhs: self.expr,
+                rhs: other.expr,
+            },
+        }
+    }
+
+    pub fn smin<T1: Fn>(self, other: Expr<T1>, k: f32) -> Expr<SminOp<T, T1>> {
+        assert!(k > 0.0, "smoothing width k must be positive");
+
+        Expr {
+            expr: SminOp {
+                lhs: self.expr,
+                rhs: other.expr,
+                k,
+            },
+        }
+    }
+
+    pub fn smax<T1: Fn>(self, other: Expr<T1>, k: f32) -> Expr<SmaxOp<T, T1>> {
+        assert!(k > 0.0, "smoothing width k must be positive");
+
+        Expr {
+            expr: SmaxOp {
+                lhs: self.expr,
+                rhs: other.expr,
+                k,
+            },
+        }
+    }
+
+    pub fn softsign(self) -> Expr<SoftsignOp<T>> {
+        Expr {
+            expr: SoftsignOp { expr: self.expr },
+        }
+    }
+
+    pub fn mish(self) -> Expr<MishOp<T>> {
+        Expr {
+            expr: MishOp { expr: self.expr },
+        }
+    }
+
+    pub fn fast_sigmoid(self) -> Expr<FastSigmoidOp<T>> {
+        Expr {
+            expr: FastSigmoidOp { expr: self.expr },
+        }
+    }
+
+    pub fn squash(self, lo: f32, hi: f32) -> Expr<SquashOp<T>> {
+        Expr {
+            expr: SquashOp {
+                expr: self.expr,
+                lo,
+                hi,
+            },
+        }
+    }
+
+    /// Map the value through a piecewise-linear table, e.g. a hardware activation
+    ///
+    /// Panics unless `breaks` and `values` have the same length of at least 2.
+    pub fn piecewise_linear<'a>(
+        self,
+        breaks: &'a [f32],
+        values: &'a [f32],
+    ) -> Expr<PiecewiseLinearOp<'a, T>> {
+        assert!(
+            breaks.len() == values.len() && breaks.len() >= 2,
+            "a piecewise-linear table needs matching breaks and values, at least 2 of each"
+        );
+
+        Expr {
+            expr: PiecewiseLinearOp {
+                expr: self.expr,
+                breaks,
+                values,
+            },
+        }
+    }
+
+    pub fn dropout(self, p: f32, seed: u64) -> Expr<DropoutOp<T>> {
+        assert!(
+            (0.0..1.0).contains(&p),
+            "dropout probability must be in [0, 1)"
+        );
+
+        Expr {
+            expr: DropoutOp {
+                expr: self.expr,
+                p,
+                seed,
+            },
+        }
+    }
+
+    pub fn pseudo_huber(self, delta: f32) -> Expr<PseudoHuberOp<T>> {
+        Expr {
+            expr: PseudoHuberOp {
+                expr: self.expr,
+                delta,
+            },
+        }
+    }
+
+    pub fn ensure_positive(self, eps: f32) -> Expr<EnsurePositiveOp<T>> {
+        Expr {
+            expr: EnsurePositiveOp {
+                expr: self.expr,
+                eps,
+            },
+        }
+    }
+
+    pub fn sqrt_safe(self, eps: f32) -> Expr<SqrtSafeOp<T>> {
+        Expr {
+            expr: SqrtSafeOp {
+                expr: self.expr,
+                eps,
+            },
+        }
+    }
+
+    pub fn sanitize(self) -> Expr<SanitizeOp<T>> {
+        Expr {
+            expr: SanitizeOp { expr: self.expr },
+        }
+    }
+
+    pub fn override_grad<F: std::ops::Fn(f32) -> f32>(
+        self,
+        surrogate: F,
+    ) -> Expr<OverrideGradOp<T, F>> {
+        Expr {
+            expr: OverrideGradOp {
+                expr: self.expr,
+                surrogate,
+            },
+        }
+    }
+
+    pub fn clamp_deriv(self, allow_negative: bool, allow_positive: bool) -> Expr<ClampDerivOp<T>> {
+        Expr {
+            expr: ClampDerivOp {
+                expr: self.expr,
+                allow_negative,
+                allow_positive,
+            },
+        }
+    }
+
+    pub fn round_to_ste(self, step: f32) -> Expr<RoundToSteOp<T>> {
+        Expr {
+            expr: RoundToSteOp {
+                expr: self.expr,
+                step,
+            },
+        }
+    }
+
+    pub fn snap_input(self, step: f32) -> Expr<SnapInputOp<T>> {
+        Expr {
+            expr: SnapInputOp {
+                expr: self.expr,
+                step,
+            },
+        }
+    }
+
+    pub fn scale_grad(self, factor: f32) -> Expr<ScaleGradOp<T>> {
+        Expr {
+            expr: ScaleGradOp {
+                expr: self.expr,
+                factor,
+            },
+        }
+    }
+
+    /// Loss scaling for mixed-precision training: `scale_grad` applied at the
+    /// loss, so small gradients stay representable in low precision
+    pub fn loss_scale(self, factor: f32) -> Expr<ScaleGradOp<T>> {
+        self.scale_grad(factor)
+    }
+
+    pub fn grad_sign(self) -> Expr<GradSignOp<T>> {
+        Expr {
+            expr: GradSignOp { expr: self.expr },
+        }
+    }
+
+    pub fn robust_deriv(self, h: f32) -> Expr<RobustDerivOp<T>> {
+        Expr {
+            expr: RobustDerivOp { expr: self.expr, h },
+        }
+    }
+
+    pub fn memoize(self) -> Expr<MemoOp<T>> {
+        Expr {
+            expr: MemoOp {
+                expr: self.expr,
+                id: NEXT_MEMO_ID.fetch_add(1, Ordering::Relaxed),
+            },
+        }
+    }
+
+    pub fn memo_scope(self) -> Expr<MemoScopeOp<T>> {
+        Expr {
+            expr: MemoScopeOp { expr: self.expr },
+        }
+    }
+
+    pub fn compose<T1: Fn>(self, other: Expr<T1>) -> Expr<ComposeOp<T, T1>> {
+        Expr {
+            expr: ComposeOp {
                 lhs: self.expr,
-                rhs: rhs.expr,
+                rhs: other.expr,
+            },
+        }
+    }
+
+    /// Restrict to the line `x0 + a * dir`, as an expression in `a`
+    pub fn line_restrict(self, x0: f32, dir: f32) -> Expr<ComposeOp<T, Affine>> {
+        self.compose(dir * X + x0)
+    }
+
+    pub fn periodic_extend(self, period: f32) -> Expr<PeriodicOp<T>> {
+        Expr {
+            expr: PeriodicOp {
+                expr: self.expr,
+                period,
+            },
+        }
+    }
+}
+
+impl<T: Fn> Expr<T> {
+    /// Like `eval`, but the value and derivative can't be swapped by mistake
+    pub fn eval_named(self, input: f32) -> Eval {
+        let (value, derivative) = self.eval(input);
+
+        Eval { value, derivative }
+    }
+
+    /// Relative condition number `|x * f'(x) / f(x)|` at `x`
+    pub fn sensitivity(self, x: f32) -> f32 {
+        let (y, dy) = self.eval(x);
+
+        (x * dy / y).abs()
+    }
+
+    /// Evaluate at `x` and propagate its standard deviation `sigma_x` to
+    /// first order, returning `(f(x), |f'(x)| * sigma_x)`
+    pub fn eval_uncertainty(self, x: f32, sigma_x: f32) -> (f32, f32) {
+        let (y, dy) = self.eval(x);
+
+        (y, dy.abs() * sigma_x)
+    }
+
+    /// The tangent line `f(x0) + f'(x0)(x - x0)` as an expression
+    pub fn tangent_at(self, x0: f32) -> Expr<Affine> {
+        let (y, dy) = self.eval(x0);
+
+        dy * X + (y - dy * x0)
+    }
+
+    /// Evaluate a positive-valued product expression by accumulating its
+    /// factors in log space and exponentiating at the end, so intermediate
+    /// products can't overflow or underflow
+    ///
+    /// This only holds through the ops that override `Fn::eval_log`, any other
+    /// op in between is evaluated directly.
+    pub fn eval_logspace(self, input: f32) -> (f32, f32) {
+        let (log, dlog) = self.expr.eval_log(input);
+        let y = log.exp();
+
+        // A zero log-derivative keeps the derivative 0 even if y overflowed
+        (y, if dlog == 0.0 { 0.0 } else { y * dlog })
+    }
+
+    /// Add the derivative at `input` to an accumulator
+    pub fn add_deriv_into(self, input: f32, acc: &mut f32) {
+        *acc += self.eval(input).1;
+    }
+}
+
+impl<T: Fn + Copy> Expr<T> {
+    /// Sine and cosine of the same argument, where the argument and its
+    /// `sin_cos` are computed only once per input for both of them (see
+    /// `memoize` for how long results are kept)
+    pub fn sincos(self) -> SinCos<T> {
+        let id = NEXT_MEMO_ID.fetch_add(1, Ordering::Relaxed);
+        let half = |cos| Expr {
+            expr: SinCosOp {
+                expr: self.expr,
+                id,
+                cos,
             },
+        };
+
+        (half(false), half(true))
+    }
+
+    /// Evaluate a batch of inputs into an array of (value, derivative) pairs
+    pub fn eval_batch_aos(self, inputs: &[f32]) -> Vec<(f32, f32)> {
+        inputs.iter().map(|&input| self.eval(input)).collect()
+    }
+
+    /// Evaluate a batch of inputs into separate value and derivative buffers
+    pub fn eval_batch_soa(self, inputs: &[f32]) -> (Vec<f32>, Vec<f32>) {
+        inputs.iter().map(|&input| self.eval(input)).unzip()
+    }
+
+    /// Evaluate a batch of inputs, also counting the results whose value or
+    /// derivative is NaN or infinite
+    pub fn eval_batch_counted(self, inputs: &[f32]) -> (Vec<(f32, f32)>, usize) {
+        let outputs = self.eval_batch_aos(inputs);
+        let non_finite = outputs
+            .iter()
+            .filter(|(y, dy)| !y.is_finite() || !dy.is_finite())
+            .count();
+
+        (outputs, non_finite)
+    }
+
+    /// Evaluate a batch of inputs, evaluating each distinct input only once
+    pub fn eval_batch_memo(self, inputs: &[f32]) -> Vec<(f32, f32)> {
+        let mut cache = HashMap::new();
+
+        inputs
+            .iter()
+            .map(|&input| {
+                *cache
+                    .entry(input.to_bits())
+                    .or_insert_with(|| self.eval(input))
+            })
+            .collect()
+    }
+
+    /// Find a root with `iters` steps of the secant method from `x0` and `x1`,
+    /// using values only
+    pub fn secant(self, x0: f32, x1: f32, iters: usize) -> f32 {
+        let (mut x0, mut x1) = (x0, x1);
+        let (mut y0, _) = self.eval(x0);
+
+        for _ in 0..iters {
+            let (y1, _) = self.eval(x1);
+
+            if y1 == y0 {
+                break;
+            }
+
+            let x2 = x1 - y1 * (x1 - x0) / (y1 - y0);
+
+            (x0, y0) = (x1, y1);
+            x1 = x2;
+        }
+
+        x1
+    }
+
+    /// Largest `|f(g(y)) - y|` over `inputs`, where `g` is the claimed inverse
+    pub fn check_inverse<T1: Fn + Copy>(self, inverse: Expr<T1>, inputs: &[f32]) -> f32 {
+        inputs
+            .iter()
+            .map(|&y| (self.eval(inverse.eval(y).0).0 - y).abs())
+            .fold(0.0, f32::max)
+    }
+
+    /// Find a root with Newton's method from `x0`, succeeding once
+    /// `|f(x)| <= tol` within `max_iters` steps
+    pub fn newton_checked(self, x0: f32, max_iters: usize, tol: f32) -> Result<f32, SolveError> {
+        let mut x = x0;
+
+        for _ in 0..max_iters {
+            let (y, dy) = self.eval(x);
+
+            if y.abs() <= tol {
+                return Ok(x);
+            }
+
+            if dy == 0.0 {
+                return Err(SolveError::ZeroDerivative {
+                    estimate: x,
+                    residual: y,
+                });
+            }
+
+            x -= y / dy;
+        }
+
+        let (y, _) = self.eval(x);
+
+        if y.abs() <= tol {
+            Ok(x)
+        } else {
+            Err(SolveError::NotConverged {
+                estimate: x,
+                residual: y,
+            })
+        }
+    }
+
+    /// Sample the expression at `n` evenly spaced points on `[lo, hi]` into a
+    /// table that is cheap to evaluate, see `TableFn` for the error bound
+    ///
+    /// Panics if `n < 2`.
+    pub fn tabulate(self, lo: f32, hi: f32, n: usize) -> TableFn {
+        assert!(n >= 2, "a table needs at least 2 samples");
+
+        let step = (hi - lo) / (n - 1) as f32;
+        let values = (0..n).map(|i| self.eval(lo + i as f32 * step).0).collect();
+
+        TableFn { lo, step, values }
+    }
+
+    /// Take one gradient descent step from `x`, backtracking from `init_step`
+    /// until the Armijo sufficient decrease condition holds
+    ///
+    /// Returns `x` unchanged if no step size decreases the value enough.
+    pub fn line_search_step(self, x: f32, init_step: f32) -> f32 {
+        const C: f32 = 1e-4;
+        const SHRINK: f32 = 0.5;
+        const MAX_TRIES: usize = 50;
+
+        let (y, dy) = self.eval(x);
+        let mut step = init_step;
+
+        for _ in 0..MAX_TRIES {
+            let next = x - step * dy;
+
+            if self.eval(next).0 <= y - C * step * dy * dy {
+                return next;
+            }
+
+            step *= SHRINK;
+        }
+
+        x
+    }
+
+    /// Sample `(x, f(x))` points on `[lo, hi]` for plotting, bisecting segments
+    /// until their midpoint is within `tol` of linear interpolation, so
+    /// points concentrate where the function curves sharply
+    pub fn adaptive_sample(self, lo: f32, hi: f32, tol: f32) -> Vec<(f32, f32)> {
+        const SEGMENTS: usize = 8;
+        const MAX_DEPTH: u32 = 12;
+
+        let sample = |x: f32| (x, self.eval(x).0);
+        let grid: Vec<_> = (0..=SEGMENTS)
+            .map(|i| sample(lo + (hi - lo) * i as f32 / SEGMENTS as f32))
+            .collect();
+
+        // Segments are popped left to right, each adding its right endpoint
+        let mut stack: Vec<_> = grid.windows(2).rev().map(|w| (w[0], w[1], 0)).collect();
+        let mut points = vec![grid[0]];
+
+        while let Some((a, b, depth)) = stack.pop() {
+            let mid = sample(0.5 * (a.0 + b.0));
+
+            if depth < MAX_DEPTH && (mid.1 - 0.5 * (a.1 + b.1)).abs() > tol {
+                stack.push((mid, b, depth + 1));
+                stack.push((a, mid, depth + 1));
+            } else {
+                points.push(b);
+            }
+        }
+
+        points
+    }
+
+    /// Evaluate at `input` and check the derivative against a central finite
+    /// difference, reporting whether they agree within `tol`
+    ///
+    /// This costs 3 evaluations instead of 1.
+    pub fn eval_verified(self, input: f32, tol: f32) -> (f32, f32, bool) {
+        let (y, dy) = self.eval(input);
+
+        // Balances truncation and rounding error of the f32 central difference
+        let h = f32::EPSILON.cbrt() * input.abs().max(1.0);
+        let (y1, _) = self.eval(input + h);
+        let (y0, _) = self.eval(input - h);
+
+        (y, dy, (dy - (y1 - y0) / (2.0 * h)).abs() <= tol)
+    }
+
+    /// Arc length of the graph over `[a, b]`, the integral of `sqrt(1 + f'^2)`
+    /// by Simpson's rule on `n` intervals (rounded up to an even count)
+    ///
+    /// Its derivative with respect to `b` is the integrand at `b`,
+    /// `sqrt(1 + f'(b)^2)`.
+    pub fn arc_length(self, a: f32, b: f32, n: usize) -> f32 {
+        let n = (n.max(2) + 1) & !1;
+        let h = (b - a) / n as f32;
+
+        let integrand = |x: f32| {
+            let (_, dy) = self.eval(x);
+            (1.0 + dy * dy).sqrt()
+        };
+
+        let mut sum = integrand(a) + integrand(b);
+
+        for i in 1..n {
+            let weight = if i % 2 == 1 { 4.0 } else { 2.0 };
+            sum += weight * integrand(a + h * i as f32);
+        }
+
+        sum * h / 3.0
+    }
+
+    /// Monte Carlo estimate of the integral over `[a, b]` and its standard
+    /// error, drawing `samples` uniform points from a generator seeded with
+    /// `seed`
+    ///
+    /// Panics if `samples` is 0.
+    pub fn mc_integrate(self, a: f32, b: f32, samples: usize, seed: u64) -> (f32, f32) {
+        assert!(
+            samples > 0,
+            "Monte Carlo integration needs at least 1 sample"
+        );
+
+        let mut rng = Rng::new(seed);
+        let width = b - a;
+
+        // Welford's running mean and variance of the sampled values
+        let mut mean = 0.0;
+        let mut m2 = 0.0;
+
+        for n in 1..=samples {
+            let (y, _) = self.eval(a + width * rng.next_f32());
+            let delta = y - mean;
+
+            mean += delta / n as f32;
+            m2 += delta * (y - mean);
+        }
+
+        let variance = m2 / samples.saturating_sub(1).max(1) as f32;
+
+        (
+            width * mean,
+            width.abs() * (variance / samples as f32).sqrt(),
+        )
+    }
+
+    /// Average value and derivative over `samples` Gaussian perturbations of
+    /// `input` with standard deviation `sigma`, seeded with `seed`
+    ///
+    /// Panics if `samples` is 0.
+    pub fn smoothed_grad(self, input: f32, sigma: f32, samples: usize, seed: u64) -> (f32, f32) {
+        assert!(samples > 0, "randomized smoothing needs at least 1 sample");
+
+        let mut rng = Rng::new(seed);
+        let mut value = 0.0;
+        let mut derivative = 0.0;
+
+        for _ in 0..samples {
+            let (y, dy) = self.eval(input + sigma * rng.next_normal());
+
+            value += y;
+            derivative += dy;
+        }
+
+        (value / samples as f32, derivative / samples as f32)
+    }
+}
+
+/// Raise a constant base to an expression, `base^u`
+pub fn base_pow<T: Fn>(base: f32, exponent: Expr<T>) -> Expr<BasePowOp<T>> {
+    Expr {
+        expr: BasePowOp {
+            expr: exponent.expr,
+            base,
+        },
+    }
+}
+
+/// Sum the terms of a series at `input` until a nonzero term changes the value
+/// by less than `tol`, or `max_terms` terms have been added
+///
+/// Terms that are exactly 0, like the even terms of an odd series, are added
+/// but never stop the sum.
+pub fn sum_until<T: Fn>(
+    mut term: impl FnMut(usize) -> Expr<T>,
+    input: f32,
+    tol: f32,
+    max_terms: usize,
+) -> (f32, f32) {
+    let mut value = 0.0;
+    let mut derivative = 0.0;
+
+    for n in 0..max_terms {
+        let (y, dy) = term(n).eval(input);
+
+        value += y;
+        derivative += dy;
+
+        if y != 0.0 && y.abs() < tol {
+            break;
+        }
+    }
+
+    (value, derivative)
+}
+
+/// Compose a chain of expressions as `exprs[0](exprs[1](...(x)))`
+///
+/// The chain is walked with a loop, so its length is not limited by the
+/// stack like nesting `compose` calls is.
+pub fn compose_chain<T: Fn>(exprs: &[Expr<T>]) -> Expr<ComposeChainOp<'_, T>> {
+    Expr {
+        expr: ComposeChainOp { exprs },
+    }
+}
+
+/// Multiply a sequence of expressions as a balanced binary tree, so the
+/// evaluation depth grows with `log2(n)` rather than `n`
+pub fn balanced_product<T: Fn>(terms: &[Expr<T>]) -> Expr<BalancedProductOp<'_, T>> {
+    Expr {
+        expr: BalancedProductOp { terms },
+    }
+}
+
+/// Build a piecewise expression using `segments[i]` between `breaks[i - 1]`
+/// and `breaks[i]`, a breakpoint itself belongs to the segment on its right
+///
+/// Continuity at the breakpoints is up to the caller. Panics unless there is
+/// exactly one more segment than breakpoints.
+///
+/// All segments have the same type, to use different formulas wrap each one
+/// with `from_fn(autodiff_fn!(...))`.
+pub fn piecewise<'a, T: Fn>(
+    breaks: &'a [f32],
+    segments: &'a [Expr<T>],
+) -> Expr<PiecewiseOp<'a, T>> {
+    assert_eq!(
+        segments.len(),
+        breaks.len() + 1,
+        "a piecewise expression needs one more segment than breakpoints"
+    );
+
+    Expr {
+        expr: PiecewiseOp { breaks, segments },
+    }
+}
+
+/// Wrap a plain function, e.g. one made by `autodiff_fn!`, into an expression
+///
+/// Expressions made from different formulas this way all have the same type,
+/// so they can share one slice in `piecewise`, `balanced_product` or
+/// `total_variation`.
+pub fn from_fn(f: fn(f32) -> (f32, f32)) -> Expr<fn(f32) -> (f32, f32)> {
+    Expr { expr: f }
+}
+
+/// Penalize jumps between consecutive expressions with `sum(|e_(i+1) - e_i|)`
+pub fn total_variation<T: Fn>(exprs: &[Expr<T>]) -> Expr<TotalVariationOp<'_, T>> {
+    Expr {
+        expr: TotalVariationOp { exprs },
+    }
+}
+
+/// Build the Bezier curve with the given control points, in the parameter `t`
+pub fn bezier(control_points: &[f32]) -> Expr<BezierOp<'_>> {
+    Expr {
+        expr: BezierOp {
+            points: control_points,
+        },
+    }
+}
+
+/// Build the series `sum(c_k * p_k(x))` for the polynomials `p_k` of a basis
+pub fn from_basis(basis: Basis, coeffs: &[f32]) -> Expr<BasisOp<'_>> {
+    Expr {
+        expr: BasisOp { basis, coeffs },
+    }
+}
+
+/// Gaussian kernel `e^(-x^2/(2 * sigma^2))`, peaking at 1 for x = 0
+pub fn gaussian_kernel(sigma: f32) -> Expr<impl Fn + Copy> {
+    (X * X * (-0.5 / (sigma * sigma))).exp()
+}
+
+/// Lorentzian `gamma^2/(x^2 + gamma^2)`, peaking at 1 for x = 0
+pub fn lorentzian(gamma: f32) -> Expr<impl Fn + Copy> {
+    gamma * gamma / (X * X + gamma * gamma)
+}
+
+/// Exponential decay `e^(-x/tau)` with time constant `tau`
+pub fn exponential_decay(tau: f32) -> Expr<impl Fn + Copy> {
+    (X * (-1.0 / tau)).exp()
+}
+
+/// A parametric curve `(x(t), y(t))` in the plane
+///
+/// The components are evaluated independently. A sub-expression used by both
+/// is evaluated once per component unless it is wrapped in `memoize`.
+#[derive(Clone, Copy)]
+pub struct Curve2<T1: Fn, T2: Fn> {
+    pub x: Expr<T1>,
+    pub y: Expr<T2>,
+}
+
+impl<T1: Fn, T2: Fn> Curve2<T1, T2> {
+    /// Value and derivative of each component at `t`
+    pub fn eval(self, t: f32) -> ((f32, f32), (f32, f32)) {
+        (self.x.eval(t), self.y.eval(t))
+    }
+
+    /// The velocity vector `(x'(t), y'(t))`
+    pub fn tangent(self, t: f32) -> (f32, f32) {
+        let ((_, dx), (_, dy)) = self.eval(t);
+
+        (dx, dy)
+    }
+}
+
+/// The identity function f(x) = x
+pub const X: Expr<Var> = Expr { expr: Var {} };
+
+/// Wraps an expression into a plain `fn(f32) -> (f32, f32)`
+///
+/// Every formula gets the same nameable type, so different formulas can be
+/// stored together, e.g. in a table or a struct field, without generics. The
+/// formula is inlined into the function just like it is into any `eval` call,
+/// but calling through the pointer is an indirect call, so this is not faster
+/// than `eval` (see `benches/autodiff_fn.rs`).
+///
+/// The formula is compiled inside a nested `fn` item, so it can only refer to
+/// constants and items, not local variables: `let a = 2.0;
+/// autodiff_fn!(a * X)` fails with E0434.
+#[macro_export]
+macro_rules! autodiff_fn {
+    ($expr:expr) => {{
+        fn fused(input: f32) -> (f32, f32) {
+            $crate::Fn::eval($expr, input)
+        }
+
+        fused as fn(f32) -> (f32, f32)
+    }};
+}
+
+/// Look up an activation applied to `X` by name, for choosing it at runtime
+///
+/// The known names are `"sigmoid"`, `"softsign"`, `"mish"` and
+/// `"fast_sigmoid"`. Any other name, e.g. `"relu"` or `"gelu"`, is an
+/// `UnknownActivation`.
+pub fn activation(name: &str) -> Result<Activation, UnknownActivation> {
+    match name {
+        // sigmoid(x) = (tanh(x/2) + 1)/2
+        "sigmoid" => Ok(autodiff_fn!((X * 0.5).squash(0.0, 1.0))),
+        "softsign" => Ok(autodiff_fn!(X.softsign())),
+        "mish" => Ok(autodiff_fn!(X.mish())),
+        "fast_sigmoid" => Ok(autodiff_fn!(X.fast_sigmoid())),
+        _ => Err(UnknownActivation(name.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn autodiff_fn_matches_eval() {
+        const K: f32 = 2.0;
+
+        let f = X.pow(3.0) / 2.0 + (K * X).sin();
+        let fused = autodiff_fn!(X.pow(3.0) / 2.0 + (K * X).sin());
+
+        for x in [-2.0, 0.0, 0.5, 3.0] {
+            assert_eq!(fused(x), f.eval(x));
+        }
+    }
+
+    #[test]
+    fn batch_layouts_match() {
+        let f = X.sin() * X;
+        let inputs = [-1.0, 0.0, 0.5, 2.0];
+
+        let aos = f.eval_batch_aos(&inputs);
+        let (values, derivatives) = f.eval_batch_soa(&inputs);
+
+        assert_eq!(aos, values.into_iter().zip(derivatives).collect::<Vec<_>>());
+    }
+
+    #[track_caller]
+    fn assert_close(actual: f32, expected: f32, tol: f32) {
+        assert!(
+            (actual - expected).abs() <= tol,
+            "{actual} is not within {tol} of {expected}"
+        );
+    }
+
+    #[test]
+    fn sum_until_alternating_series() {
+        // e^-x = sum((-x)^n/n!)
+        let mut factorial = 1.0;
+        let term = |n: usize| {
+            if n > 0 {
+                factorial *= n as f32;
+            }
+
+            X.pow(n as f32) * ((-1.0f32).powi(n as i32) / factorial)
+        };
+
+        let (y, dy) = sum_until(term, 1.0, 1e-8, 50);
+
+        assert_close(y, (-1.0f32).exp(), 1e-6);
+        assert_close(dy, -(-1.0f32).exp(), 1e-6);
+
+        // x/(1 - x^2) = sum(x^(2k+1)), written with a zero even term
+        let term = |n: usize| X.pow(n as f32) * (n % 2) as f32;
+        let (y, dy) = sum_until(term, 0.5, 1e-8, 50);
+
+        assert_close(y, 2.0 / 3.0, 1e-6);
+        assert_close(dy, 1.25 / 0.5625, 1e-5);
+    }
+
+    #[test]
+    fn basis_reference_values() {
+        // 1 + 2x + 3x^2 at x = 2
+        let (y, dy) = from_basis(Basis::Monomial, &[1.0, 2.0, 3.0]).eval(2.0);
+        assert_close(y, 17.0, 1e-5);
+        assert_close(dy, 14.0, 1e-5);
+
+        // T_3(x) = 4x^3 - 3x at x = 0.3
+        let (y, dy) = from_basis(Basis::Chebyshev, &[0.0, 0.0, 0.0, 1.0]).eval(0.3);
+        assert_close(y, -0.792, 1e-5);
+        assert_close(dy, -1.92, 1e-5);
+
+        // P_2(x) = (3x^2 - 1)/2 at x = 0.3
+        let (y, dy) = from_basis(Basis::Legendre, &[0.0, 0.0, 1.0]).eval(0.3);
+        assert_close(y, -0.365, 1e-5);
+        assert_close(dy, 0.9, 1e-5);
+    }
+
+    #[test]
+    fn rem_wraps_value_and_keeps_derivative() {
+        let f = (2.0 * X) % 3.0;
+
+        for (x, expected) in [(0.5, 1.0), (2.0, 1.0), (-1.0, 1.0), (-0.25, 2.5)] {
+            let (y, dy) = f.eval(x);
+
+            assert_close(y, expected, 1e-6);
+            assert_eq!(dy, 2.0);
+        }
+    }
+
+    #[test]
+    fn angle_diff_is_continuous_across_pi() {
+        use std::f32::consts::{PI, TAU};
+
+        let target = PI - 0.05;
+        let f = X.angle_diff(target);
+
+        // The same angles, on both sides of the +-pi boundary
+        for x in [PI - 0.1, PI - 0.01, PI + 0.01, PI + 0.1] {
+            for angle in [x, x - TAU] {
+                let (y, dy) = f.eval(angle);
+
+                assert_close(y, x - target, 1e-5);
+                assert_eq!(dy, 1.0);
+            }
+        }
+    }
+
+    #[test]
+    fn override_grad_surrogates() {
+        // Rounded value with a pass-through surrogate
+        let f = X.round_to_ste(1.0).override_grad(|_| 1.0);
+        assert_eq!(f.eval(2.3), (2.0, 1.0));
+
+        // The surrogate is evaluated at the value and scales u'
+        let f = (3.0 * X).override_grad(|y| 2.0 * y);
+        assert_eq!(f.eval(1.0), (3.0, 18.0));
+    }
+
+    #[test]
+    fn mc_integrate_converges() {
+        // The integral of x^2 over [0, 3] is 9
+        let f = X * X;
+
+        let (coarse, coarse_error) = f.mc_integrate(0.0, 3.0, 100, 1);
+        let (fine, fine_error) = f.mc_integrate(0.0, 3.0, 100_000, 1);
+
+        assert!(fine_error < coarse_error);
+        assert!((fine - 9.0).abs() < (coarse - 9.0).abs());
+        assert!((fine - 9.0).abs() < 4.0 * fine_error);
+    }
+
+    #[test]
+    #[should_panic]
+    fn mc_integrate_needs_samples() {
+        X.mc_integrate(0.0, 1.0, 0, 1);
+    }
+
+    #[test]
+    fn exp_saturating_caps() {
+        let f = X.exp_saturating(10.0);
+
+        let (y, dy) = f.eval(1.0);
+        assert_close(y, 1.0f32.exp(), 1e-6);
+        assert_close(dy, 1.0f32.exp(), 1e-6);
+
+        assert_eq!(f.eval(5.0), (10.0, 0.0));
+        assert_eq!(f.eval(1000.0), (10.0, 0.0));
+
+        let (y, dy) = f.eval(f32::NAN);
+        assert!(y.is_nan() && dy.is_nan());
+    }
+
+    #[test]
+    fn sensitivity_of_ln() {
+        for x in [0.5f32, 2.0, 10.0] {
+            assert_close(X.ln().sensitivity(x), 1.0 / x.ln().abs(), 1e-5);
+        }
+    }
+
+    #[test]
+    fn clamp_deriv_masks() {
+        let f = X * X;
+
+        for (allow_negative, allow_positive) in
+            [(false, false), (false, true), (true, false), (true, true)]
+        {
+            let g = f.clamp_deriv(allow_negative, allow_positive);
+
+            let expected_negative = if allow_negative { -4.0 } else { 0.0 };
+            let expected_positive = if allow_positive { 4.0 } else { 0.0 };
+
+            assert_eq!(g.eval(-2.0), (4.0, expected_negative));
+            assert_eq!(g.eval(2.0), (4.0, expected_positive));
+        }
+    }
+
+    #[test]
+    fn curve2_unit_circle() {
+        let circle = Curve2 {
+            x: X.cos(),
+            y: X.sin(),
+        };
+
+        for t in [0.0f32, 0.7, 2.0, -3.0] {
+            let ((x, _), (y, _)) = circle.eval(t);
+            let (dx, dy) = circle.tangent(t);
+
+            assert_close(x * x + y * y, 1.0, 1e-6);
+            assert_close(dx, -t.sin(), 1e-6);
+            assert_close(dy, t.cos(), 1e-6);
+            assert_close(x * dx + y * dy, 0.0, 1e-6);
+        }
+    }
+
+    // Central difference of the value, to check derivatives against
+    fn central_diff(f: impl Fn + Copy, x: f32) -> f32 {
+        let h = 1e-3;
+
+        (f.eval(x + h).0 - f.eval(x - h).0) / (2.0 * h)
+    }
+
+    #[test]
+    fn softsign_range_and_derivative() {
+        let f = X.softsign();
+
+        for x in [-1e6, -3.0, -0.5, 0.0, 0.5, 3.0, 1e6] {
+            let (y, _) = f.eval(x);
+            assert!(-1.0 < y && y < 1.0);
+        }
+
+        for x in [-3.0, -0.5, 0.5, 3.0] {
+            assert_close(f.eval(x).1, central_diff(f, x), 1e-3);
+        }
+    }
+
+    #[test]
+    fn robust_deriv_falls_back_when_analytic_is_nan() {
+        // sqrt(x^2) has the analytic derivative 0 * inf = NaN at 0
+        let f = (X * X).sqrt();
+        assert!(f.eval(0.0).1.is_nan());
+
+        assert_eq!(f.robust_deriv(1e-3).eval(0.0), (0.0, 0.0));
+        assert_eq!(f.robust_deriv(1e-3).eval(2.0), f.eval(2.0));
+    }
+
+    #[test]
+    fn ensure_positive_stays_above_eps() {
+        let eps = 1e-3;
+        let f = X.ensure_positive(eps);
+
+        for x in [-1e6, -100.0, -5.0, 0.0, 5.0, 100.0] {
+            let (y, dy) = f.eval(x);
+
+            assert!(y > eps);
+            assert!((0.0..=1.0).contains(&dy));
+        }
+
+        // The softplus is far below the spacing of floats around 1e6
+        assert!(X.ensure_positive(1e6).eval(-5.0).0 > 1e6);
+    }
+
+    #[test]
+    fn cubic_spline_is_c1_at_knots() {
+        let knots = [(0.0, 0.0), (1.0, 2.0), (2.0, 1.0), (4.0, 3.0), (5.0, 0.0)];
+        let spline = CubicSpline::new(&knots);
+        let f = spline.expr();
+
+        for &(x, y) in &knots {
+            assert_close(f.eval(x).0, y, 1e-5);
+        }
+
+        // A knot belongs to the segment on its right, the float just below it
+        // is still on the segment to its left
+        for &(x, _) in &knots[1..knots.len() - 1] {
+            let (y0, dy0) = f.eval(f32::from_bits(x.to_bits() - 1));
+            let (y1, dy1) = f.eval(x);
+
+            assert_close(y0, y1, 1e-5);
+            assert_close(dy0, dy1, 1e-4);
+        }
+    }
+
+    #[test]
+    fn pow_half_matches_powf() {
+        let f = X.pow_half(3);
+
+        for x in [1e-4f32, 0.3, 1.0, 2.5, 100.0] {
+            let (y, dy) = f.eval(x);
+
+            assert_close(y, x.powf(1.5), 1e-6 * x.powf(1.5));
+            assert_close(dy, 1.5 * x.sqrt(), 1e-6 * x.sqrt());
+        }
+    }
+
+    #[test]
+    fn eval_batch_counted_across_ln_domain() {
+        let (outputs, non_finite) = X.ln().eval_batch_counted(&[-1.0, 0.0, 0.5, 1.0, 2.0]);
+
+        assert_eq!(outputs.len(), 5);
+        assert_eq!(non_finite, 2);
+    }
+
+    #[test]
+    fn total_variation_subgradient() {
+        let exprs = [X * 1.0, X * 2.0, X * 0.5];
+        let f = total_variation(&exprs);
+
+        // |2 - 1| + |0.5 - 2|
+        assert_eq!(f.eval(1.0), (2.5, 2.5));
+
+        // All values are equal at 0, each jump contributes the subgradient 0
+        assert_eq!(f.eval(0.0), (0.0, 0.0));
+    }
+
+    #[test]
+    fn periodic_extend_repeats() {
+        let f = (X * X).periodic_extend(2.0);
+
+        for x in [-3.5, -1.5, 0.5, 2.5, 4.5] {
+            let (y, dy) = f.eval(x);
+
+            assert_close(y, 0.25, 1e-6);
+            assert_close(dy, 1.0, 1e-6);
         }
     }
-}
 
-impl<T: Fn> Sub<f32> for Expr<T> {
-    type Output = Expr<SubOp<T, Const>>;
+    #[test]
+    fn add_deriv_into_accumulates() {
+        let f = X * X;
+        let mut acc = 0.0;
 
-    fn sub(self, rhs: f32) -> Self::Output {
-        Self::Output {
-            expr: SubOp {
-                lhs: self.expr,
-                rhs: Const { value: rhs },
-            },
+        f.add_deriv_into(1.0, &mut acc);
+        f.add_deriv_into(1.0, &mut acc);
+        f.add_deriv_into(-3.0, &mut acc);
+
+        assert_eq!(acc, -2.0);
+    }
+
+    #[test]
+    fn logaddexp_stable_and_overflow() {
+        let f = X.logaddexp(X * 2.0);
+        let naive = (X.exp() + (X * 2.0).exp()).ln();
+
+        for x in [-2.0, 0.0, 1.0, 3.0] {
+            let (y, dy) = f.eval(x);
+            let (naive_y, naive_dy) = naive.eval(x);
+
+            assert_close(y, naive_y, 1e-5);
+            assert_close(dy, naive_dy, 1e-5);
+        }
+
+        // e^200 overflows, the naive form gives inf
+        assert!(naive.eval(100.0).0.is_infinite());
+
+        let (y, dy) = f.eval(100.0);
+        assert_close(y, 200.0, 1e-4);
+        assert_close(dy, 2.0, 1e-6);
+    }
+
+    #[test]
+    fn kernels_peak_and_decay() {
+        let gaussian = gaussian_kernel(1.5);
+        let lorentz = lorentzian(2.0);
+        let decay = exponential_decay(3.0);
+
+        assert_eq!(gaussian.eval(0.0), (1.0, 0.0));
+        assert_eq!(lorentz.eval(0.0), (1.0, 0.0));
+        assert_close(gaussian.eval(1.5).0, (-0.5f32).exp(), 1e-6);
+        assert_close(lorentz.eval(2.0).0, 0.5, 1e-6);
+        assert_close(decay.eval(3.0).0, (-1.0f32).exp(), 1e-6);
+
+        for x in [-2.0, -0.5, 0.5, 1.0, 4.0] {
+            assert_close(gaussian.eval(x).1, central_diff(gaussian, x), 1e-3);
+            assert_close(lorentz.eval(x).1, central_diff(lorentz, x), 1e-3);
+            assert_close(decay.eval(x).1, central_diff(decay, x), 1e-3);
         }
     }
-}
 
-impl<T: Fn> Sub<Expr<T>> for f32 {
-    type Output = Expr<SubOp<Const, T>>;
+    #[test]
+    fn tangent_touches_at_x0() {
+        let f = X.sin() * X;
+        let tangent = f.tangent_at(1.3);
 
-    fn sub(self, rhs: Expr<T>) -> Self::Output {
-        Self::Output {
-            expr: SubOp {
-                lhs: Const { value: self },
-                rhs: rhs.expr,
-            },
+        let (y, dy) = f.eval(1.3);
+        let (ty, tdy) = tangent.eval(1.3);
+
+        assert_close(ty, y, 1e-6);
+        assert_eq!(tdy, dy);
+    }
+
+    #[test]
+    fn scale_grad_is_linear() {
+        let f = X.sin() * X;
+        let (y, dy) = f.eval(0.7);
+
+        for factor in [0.0, 0.5, 2.0, -3.0] {
+            assert_eq!(f.scale_grad(factor).eval(0.7), (y, dy * factor));
+            assert_eq!(f.loss_scale(factor).eval(0.7), (y, dy * factor));
         }
     }
-}
 
-// Negation operator overloading
+    #[test]
+    fn secant_finds_sqrt_2() {
+        let root = (X * X - 2.0).secant(1.0, 2.0, 20);
 
-impl<T: Fn> Neg for Expr<T> {
-    type Output = Expr<NegOp<T>>;
+        assert_close(root, 2.0f32.sqrt(), 1e-6);
+    }
 
-    fn neg(self) -> Self::Output {
-        Self::Output {
-            expr: NegOp { expr: self.expr },
+    #[test]
+    fn compose_chain_50k_deep() {
+        const DEPTH: usize = 50_000;
+        const K: f32 = 1.00001;
+
+        // Nesting this many compose calls would overflow the stack
+        let exprs = vec![X * K; DEPTH];
+        let (y, dy) = compose_chain(&exprs).eval(2.0);
+
+        let gain = (K as f64).powi(DEPTH as i32) as f32;
+
+        assert_close(y, 2.0 * gain, 1e-3);
+        assert_close(dy, gain, 1e-3);
+    }
+
+    #[test]
+    fn grad_sign_ignores_magnitude() {
+        let f = (1000.0 * X * X).grad_sign();
+
+        assert_eq!(f.eval(3.0).1, 1.0);
+        assert_eq!(f.eval(-1e-3).1, -1.0);
+        assert_eq!(f.eval(0.0).1, 0.0);
+    }
+
+    #[test]
+    fn smoothed_grad_approaches_gradient() {
+        let (x, sigma) = (1.0f32, 0.1f32);
+        let f = X.sin();
+
+        // Smoothing sin with a Gaussian scales it by e^(-sigma^2/2)
+        let smoothed = x.cos() * (-0.5 * sigma * sigma).exp();
+
+        let (_, coarse) = f.smoothed_grad(x, sigma, 10, 1);
+        let (_, fine) = f.smoothed_grad(x, sigma, 100_000, 1);
+
+        assert!((fine - smoothed).abs() < (coarse - smoothed).abs());
+        assert_close(fine, x.cos(), 1e-2);
+    }
+
+    #[test]
+    #[should_panic]
+    fn smoothed_grad_needs_samples() {
+        X.smoothed_grad(0.0, 1.0, 0, 1);
+    }
+
+    #[test]
+    fn fast_sigmoid_tolerance() {
+        let f = X.fast_sigmoid();
+
+        for i in 0..=1000 {
+            let x = -5.0 + 10.0 * i as f32 / 1000.0;
+            let sigmoid = 1.0 / (1.0 + (-x).exp());
+
+            assert_close(f.eval(x).0, sigmoid, 0.083);
         }
     }
-}
 
-// Division operator overloading
+    #[test]
+    fn eval_named_fields() {
+        let Eval { value, derivative } = (X * X * X).eval_named(2.0);
 
-impl<T1: Fn, T2: Fn> Div<Expr<T2>> for Expr<T1> {
-    type Output = Expr<DivOp<T1, T2>>;
+        assert_eq!(value, 8.0);
+        assert_eq!(derivative, 12.0);
+    }
 
-    fn div(self, rhs: Expr<T2>) -> Self::Output {
-        Self::Output {
-            expr: DivOp {
-                lhs: self.expr,
-                rhs: rhs.expr,
-            },
+    #[test]
+    fn pow_clamped_stays_finite_near_zero() {
+        let f = X.pow_clamped(0.5, 1e-6);
+
+        for x in [1e-2, 1e-4, 1e-6, 1e-8, 0.0, -1.0] {
+            let (y, dy) = f.eval(x);
+
+            assert!(y.is_finite() && dy.is_finite());
         }
+
+        assert_eq!(f.eval(4.0), X.sqrt().eval(4.0));
     }
-}
 
-impl<T: Fn> Div<f32> for Expr<T> {
-    type Output = Expr<DivOp<T, Const>>;
+    #[test]
+    fn adaptive_sample_concentrates_on_sharp_features() {
+        // Steep around 0, nearly flat elsewhere
+        let points = (50.0 * X).atan().adaptive_sample(-1.0, 1.0, 1e-3);
+        let count = |lo: f32, hi: f32| points.iter().filter(|(x, _)| lo <= *x && *x < hi).count();
 
-    fn div(self, rhs: f32) -> Self::Output {
-        Self::Output {
-            expr: DivOp {
-                lhs: self.expr,
-                rhs: Const { value: rhs },
-            },
+        assert!(count(-0.1, 0.1) > 2 * count(0.8, 1.0));
+        assert!(points.windows(2).all(|w| w[0].0 < w[1].0));
+    }
+
+    #[test]
+    fn memoize_evaluates_shared_node_once_per_scope() {
+        let calls = Cell::new(0);
+        let g = X
+            .override_grad(|_| {
+                calls.set(calls.get() + 1);
+                1.0
+            })
+            .memoize();
+        let f = (g.sin() * g.cos()).memo_scope();
+
+        f.eval(0.3);
+        assert_eq!(calls.get(), 1);
+
+        // Each evaluation of the scope starts fresh
+        f.eval(0.3);
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[test]
+    fn memo_scope_forgets_stale_results() {
+        let k = Cell::new(1.0);
+        let f = (X * 1.0).override_grad(|_| k.get()).memoize().memo_scope();
+
+        assert_eq!(f.eval(2.0), (2.0, 1.0));
+
+        k.set(5.0);
+        assert_eq!(f.eval(2.0), (2.0, 5.0));
+    }
+
+    #[test]
+    fn sanitize_zeroes_non_finite() {
+        let f = X.ln().sanitize();
+
+        // ln(-1) is NaN, its derivative -1 is left alone
+        assert_eq!(f.eval(-1.0), (0.0, -1.0));
+        assert_eq!(f.eval(0.0), (0.0, 0.0));
+        assert_eq!(f.eval(1.0), (0.0, 1.0));
+    }
+
+    #[test]
+    fn pseudo_huber_shape() {
+        let f = X.pseudo_huber(1.0);
+
+        // Quadratic and smooth at 0
+        assert_eq!(f.eval(0.0), (0.0, 0.0));
+        assert_close(f.eval(1e-3).1, 1e-3, 1e-6);
+
+        // Linear with slope delta for large residuals
+        let (y, dy) = f.eval(1000.0);
+        assert_close(y, 999.0, 1e-2);
+        assert_close(dy, 1.0, 1e-6);
+        assert_close(f.eval(-1000.0).1, -1.0, 1e-6);
+    }
+
+    #[test]
+    fn eval_verified_flags_wrong_derivative() {
+        let f = X.sin() * X;
+        let (y, dy, ok) = f.eval_verified(0.8, 1e-2);
+
+        assert_eq!((y, dy), f.eval(0.8));
+        assert!(ok);
+
+        // A derivative that is off by a factor of 2
+        let wrong = f.override_grad(|_| 2.0);
+        assert!(!wrong.eval_verified(0.8, 1e-2).2);
+    }
+
+    #[test]
+    fn eval_batch_memo_skips_repeats() {
+        let calls = Cell::new(0);
+        let f = X.override_grad(|_| {
+            calls.set(calls.get() + 1);
+            1.0
+        });
+
+        let outputs = f.eval_batch_memo(&[1.0, 2.0, 1.0, 1.0, 2.0]);
+
+        assert_eq!(
+            outputs,
+            [(1.0, 1.0), (2.0, 1.0), (1.0, 1.0), (1.0, 1.0), (2.0, 1.0)]
+        );
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[test]
+    fn line_restrict_derivative() {
+        let f = X.sin() * X;
+        let (x0, dir) = (0.6, -2.5);
+
+        let (y, dy) = f.line_restrict(x0, dir).eval(0.0);
+        let (fy, fdy) = f.eval(x0);
+
+        assert_eq!(y, fy);
+        assert_close(dy, fdy * dir, 1e-6);
+    }
+
+    #[test]
+    fn smin_smax_approach_hard_min_max() {
+        let (u, v) = (X * 2.0, X + 1.0);
+
+        for x in [-1.0f32, 0.5, 1.0, 3.0] {
+            let (a, b) = (2.0 * x, x + 1.0);
+
+            assert_close(u.smin(v, 1e-3).eval(x).0, a.min(b), 1e-3);
+            assert_close(u.smax(v, 1e-3).eval(x).0, a.max(b), 1e-3);
         }
+
+        // Larger widths blend further away from the hard min and max
+        assert!(u.smin(v, 1.0).eval(1.0).0 < u.smin(v, 0.1).eval(1.0).0);
+        assert!(u.smax(v, 1.0).eval(1.0).0 > u.smax(v, 0.1).eval(1.0).0);
     }
-}
 
-impl<T: Fn> Div<Expr<T>> for f32 {
-    type Output = Expr<DivOp<Const, T>>;
+    #[test]
+    #[should_panic]
+    fn smin_needs_positive_k() {
+        X.smin(X, 0.0);
+    }
 
-    fn div(self, rhs: Expr<T>) -> Self::Output {
-        Self::Output {
-            expr: DivOp {
-                lhs: Const { value: self },
-                rhs: rhs.expr,
-            },
+    #[test]
+    #[should_panic]
+    fn smax_needs_positive_k() {
+        X.smax(X, -1.0);
+    }
+
+    #[test]
+    fn floor_div_reconstructs_with_rem() {
+        let u = X * 1.5;
+
+        for x in [-4.1f32, -0.3, 0.2, 1.9, 7.7] {
+            let (q, dq) = u.floor_div(2.0).eval(x);
+            let (r, _) = (u % 2.0).eval(x);
+
+            assert_close(q * 2.0 + r, 1.5 * x, 1e-5);
+            assert_eq!(dq, 0.0);
         }
     }
-}
 
-impl<T: Fn> Expr<T> {
-    pub fn pow(self, order: f32) -> Expr<PowOp<T>> {
-        Expr {
-            expr: PowOp {
-                expr: self.expr,
-                order,
-            },
+    #[test]
+    fn round_to_ste_quantizes_value_only() {
+        let f = (X * 3.0).round_to_ste(0.5);
+
+        assert_eq!(f.eval(0.1), (0.5, 3.0));
+        assert_eq!(f.eval(0.3), (1.0, 3.0));
+        assert_eq!(f.eval(-0.6), (-2.0, 3.0));
+    }
+
+    #[test]
+    fn eval_logspace_products() {
+        let offsets: Vec<f32> = (0..100).map(|i| (i as f32 - 50.0) * 1e-3).collect();
+        let terms: Vec<_> = offsets.iter().map(|&c| X + c).collect();
+
+        // f64 reference for the product of 100 terms near 2 and its derivative
+        let x = 2.0;
+        let product: f64 = offsets.iter().map(|&c| (x + c) as f64).product();
+        let dlog: f64 = offsets.iter().map(|&c| 1.0 / (x + c) as f64).sum();
+
+        let (y, dy) = balanced_product(&terms).eval_logspace(x);
+        assert_close(y / product as f32, 1.0, 1e-4);
+        assert_close(dy / (product * dlog) as f32, 1.0, 1e-4);
+
+        // Near 4 the product overflows f32 before the root brings it back,
+        // also through the pass-through wrappers
+        let f = balanced_product(&terms).memoize().scale_grad(1.0).pow(0.01);
+        let log: f64 = offsets.iter().map(|&c| (4.0 + c as f64).ln()).sum();
+
+        assert!(f.eval(4.0).0.is_infinite());
+        assert_close(f.eval_logspace(4.0).0, (0.01 * log).exp() as f32, 1e-5);
+
+        // A constant overflowing product keeps a zero derivative
+        let constant = [X * 0.0 + 1e20; 3];
+        assert_eq!(
+            balanced_product(&constant).eval_logspace(1.0),
+            (f32::INFINITY, 0.0)
+        );
+    }
+
+    #[test]
+    fn bezier_cubic_endpoints() {
+        let points = [1.0, 3.0, -2.0, 0.5];
+        let f = bezier(&points);
+
+        assert_close(f.eval(0.0).0, 1.0, 1e-6);
+        assert_close(f.eval(1.0).0, 0.5, 1e-6);
+        assert_close(f.eval(0.0).1, 3.0 * (3.0 - 1.0), 1e-5);
+        assert_close(f.eval(1.0).1, 3.0 * (0.5 - -2.0), 1e-5);
+
+        // Against the expanded Bernstein form
+        for t in [0.2f32, 0.5, 0.9] {
+            let s = 1.0 - t;
+            let expected = s * s * s + 9.0 * s * s * t - 6.0 * s * t * t + 0.5 * t * t * t;
+
+            assert_close(f.eval(t).0, expected, 1e-5);
+            assert_close(f.eval(t).1, central_diff(f, t), 1e-2);
         }
     }
 
-    pub fn sqrt(self) -> Expr<PowOp<T>> {
-        Expr {
-            expr: PowOp {
-                expr: self.expr,
-                order: 0.5,
-            },
+    #[test]
+    fn piecewise_three_segments() {
+        let breaks = [0.0, 1.0];
+        let segments = [
+            from_fn(autodiff_fn!(X * -1.0)),
+            from_fn(autodiff_fn!(X * X)),
+            from_fn(autodiff_fn!((X - 1.0).exp() + 1.0)),
+        ];
+        let f = piecewise(&breaks, &segments);
+
+        assert_eq!(f.eval(-0.5), (0.5, -1.0));
+        assert_eq!(f.eval(0.5), (0.25, 1.0));
+        assert_eq!(f.eval(3.0), ((X - 1.0).exp() + 1.0).eval(3.0));
+
+        // Breakpoints belong to the segment on their right
+        assert_eq!(f.eval(0.0), (0.0, 0.0));
+        assert_eq!(f.eval(1.0), (2.0, 1.0));
+    }
+
+    #[test]
+    fn loss_scale_scales_gradient_exactly() {
+        let loss = (X - 3.0).pow(2.0);
+        let (y, dy) = loss.eval(1.0);
+
+        assert_eq!(loss.loss_scale(1024.0).eval(1.0), (y, dy * 1024.0));
+    }
+
+    #[test]
+    fn balanced_product_matches_sequential() {
+        let terms: Vec<_> = (1..=16).map(|i| X + i as f32 * 0.1).collect();
+        let (y, dy) = balanced_product(&terms).eval(0.5);
+
+        let sequential = terms[1..].iter().fold(terms[0].eval(0.5), |(u, du), term| {
+            let (v, dv) = term.eval(0.5);
+            (u * v, u * dv + v * du)
+        });
+
+        assert_close(y / sequential.0, 1.0, 1e-5);
+        assert_close(dy / sequential.1, 1.0, 1e-5);
+
+        // The recursion depth is log2(n), so a long product is no problem
+        let ones = vec![X * 1.0; 100_000];
+        assert_eq!(balanced_product(&ones).eval(1.0), (1.0, 100_000.0));
+    }
+
+    #[test]
+    fn piecewise_linear_segments() {
+        let breaks = [-1.0, 0.0, 2.0];
+        let values = [0.0, 1.0, 2.0];
+        let f = X.piecewise_linear(&breaks, &values);
+
+        for (x, slope) in [(-2.0, 1.0), (-0.5, 1.0), (1.0, 0.5), (3.0, 0.5)] {
+            assert_eq!(f.eval(x).1, slope);
+        }
+
+        for (&b, &v) in breaks.iter().zip(&values) {
+            assert_close(f.eval(b - 1e-4).0, v, 1e-3);
+            assert_close(f.eval(b).0, v, 1e-6);
         }
     }
 
-    pub fn exp(self) -> Expr<ExpOp<T>> {
-        Expr {
-            expr: ExpOp { expr: self.expr },
+    #[test]
+    fn sincos_matches_sin_and_cos() {
+        let arg = X * X + 0.5;
+        let (sin, cos) = arg.sincos();
+
+        for x in [-1.0, 0.0, 0.3, 2.0] {
+            assert_eq!(sin.eval(x), arg.sin().eval(x));
+            assert_eq!(cos.eval(x), arg.cos().eval(x));
         }
     }
 
-    pub fn sin(self) -> Expr<SinOp<T>> {
-        Expr {
-            expr: SinOp { expr: self.expr },
+    #[test]
+    fn sincos_evaluates_trig_once() {
+        fn trig_evals(f: impl Fn) -> usize {
+            TRIG_EVALS.set(0);
+            f.eval(0.7);
+            TRIG_EVALS.get()
         }
+
+        let arg = X * X + 0.5;
+
+        assert_eq!(trig_evals(arg.sin() * arg.cos()), 2);
+
+        let (sin, cos) = arg.sincos();
+        assert_eq!(trig_evals(sin * cos), 1);
     }
 
-    pub fn cos(self) -> Expr<CosOp<T>> {
-        Expr {
-            expr: CosOp { expr: self.expr },
+    #[test]
+    fn squash_stays_in_range() {
+        let f = X.squash(-2.0, 3.0);
+
+        for x in [-8.0, -1.0, 0.0, 0.5, 8.0] {
+            let (y, dy) = f.eval(x);
+
+            assert!(-2.0 < y && y < 3.0);
+            assert_close(dy, central_diff(f, x), 1e-2);
         }
     }
 
-    pub fn atan(self) -> Expr<AtanOp<T>> {
-        Expr {
-            expr: AtanOp { expr: self.expr },
+    #[test]
+    fn arc_length_of_a_line() {
+        let length = (3.0 * X + 1.0).arc_length(0.0, 2.0, 10);
+
+        assert_close(length, 2.0 * 10.0f32.sqrt(), 1e-5);
+    }
+
+    #[test]
+    fn signed_pow_negative_input() {
+        let f = X.signed_pow(0.5);
+
+        assert_eq!(f.eval(-4.0), (-2.0, 0.25));
+        assert_eq!(f.eval(4.0), (2.0, 0.25));
+    }
+
+    #[test]
+    fn base_pow_matches_exp_form() {
+        let f = base_pow(3.0, X * X);
+        let manual = (X * X * 3.0f32.ln()).exp();
+
+        for x in [-1.5, 0.0, 0.7, 2.0] {
+            let (y, dy) = f.eval(x);
+            let (my, mdy) = manual.eval(x);
+
+            assert_close(y, my, 1e-5 * my);
+            assert_close(dy, mdy, 1e-5 * my);
         }
     }
 
-    pub fn ln(self) -> Expr<LnOp<T>> {
-        Expr {
-            expr: LnOp { expr: self.expr },
+    #[test]
+    fn softmax_with_pair_sums_to_one() {
+        let (a, b) = (X * 2.0, X.sin());
+        let f = a.softmax_with(b) + b.softmax_with(a);
+
+        for x in [-3.0, 0.0, 1.0, 50.0] {
+            let (y, dy) = f.eval(x);
+
+            assert_close(y, 1.0, 1e-6);
+            assert_close(dy, 0.0, 1e-6);
         }
     }
 
-    pub fn compose<T1: Fn>(self, other: Expr<T1>) -> Expr<ComposeOp<T, T1>> {
-        Expr {
-            expr: ComposeOp {
-                lhs: self.expr,
-                rhs: other.expr,
-            },
+    #[test]
+    fn line_search_step_decreases_convex_objective() {
+        let f = (X - 3.0).pow(2.0) * 5.0 + (X * 0.5).exp();
+        let mut x = -4.0;
+
+        for _ in 0..10 {
+            let next = f.line_search_step(x, 10.0);
+
+            assert!(f.eval(next).0 < f.eval(x).0);
+            x = next;
         }
     }
-}
 
-/// The identity function f(x) = x
-pub const X: Expr<Var> = Expr { expr: Var {} };
+    #[test]
+    fn eval_uncertainty_of_square() {
+        for x in [-3.0f32, 0.5, 2.0] {
+            let (y, sigma) = (X * X).eval_uncertainty(x, 0.1);
+
+            assert_eq!(y, x * x);
+            assert_close(sigma, 2.0 * x.abs() * 0.1, 1e-6);
+        }
+    }
+
+    #[test]
+    fn snap_input_evaluates_on_the_grid() {
+        let f = (X * X).snap_input(0.5);
+
+        assert_eq!(f.eval(1.3), (X * X).eval(1.5));
+        assert_eq!(f.eval(-0.2), (X * X).eval(0.0));
+    }
+
+    #[test]
+    fn gd_is_the_integral_of_sech() {
+        // The integral of sech from 0 to x is atan(sinh(x))
+        for x in [-2.0f32, 0.0, 0.5, 1.0, 3.0] {
+            let (y, dy) = X.gd().eval(x);
+
+            assert_close(y, x.sinh().atan(), 1e-6);
+            assert_close(dy, 1.0 / x.cosh(), 1e-6);
+        }
+    }
+
+    #[test]
+    fn dropout_preserves_expected_value() {
+        let f = X.sin() + 2.0;
+        let (y, dy) = f.eval(0.3);
+
+        let samples = 100_000;
+        let (mut sum, mut dsum, mut dropped) = (0.0f64, 0.0f64, 0);
+
+        for seed in 0..samples {
+            let (value, derivative) = f.dropout(0.3, seed).eval(0.3);
+
+            sum += value as f64;
+            dsum += derivative as f64;
+            dropped += (value == 0.0) as usize;
+        }
+
+        assert_close((sum / samples as f64) as f32, y, 0.02);
+        assert_close((dsum / samples as f64) as f32, dy, 0.02);
+        assert_close(dropped as f32 / samples as f32, 0.3, 0.01);
+    }
+
+    #[test]
+    fn dropout_mask_depends_on_seed_only() {
+        let a = X.dropout(0.5, 7);
+
+        // Building other nodes doesn't change the mask of a seed
+        let _ = X.dropout(0.5, 3);
+        let b = (X * 1.0).dropout(0.5, 7);
+
+        for x in [0.5, 1.0, 2.0, 3.0] {
+            assert_eq!(a.eval(x).0 == 0.0, b.eval(x).0 == 0.0);
+        }
+
+        // Different seeds agree only about half the time
+        let c = X.dropout(0.5, 8);
+        let agree = (1..=1000)
+            .filter(|&i| {
+                let x = i as f32;
+                (a.eval(x).0 == 0.0) == (c.eval(x).0 == 0.0)
+            })
+            .count();
+
+        assert!((400..600).contains(&agree));
+    }
+
+    #[test]
+    fn tabulate_stays_within_error_bound() {
+        let (lo, hi, n) = (-4.0, 4.0, 65);
+        let table = X.sin().tabulate(lo, hi, n);
+
+        // |sin''| <= 1, so the bound is h^2/8
+        let h = (hi - lo) / (n - 1) as f32;
+        let bound = h * h / 8.0;
+
+        for i in 0..=1000 {
+            let x = lo + (hi - lo) * i as f32 / 1000.0;
+            let err = (table.expr().eval(x).0 - x.sin()).abs();
+            assert!(err <= bound, "error {err} at x = {x} exceeds {bound}");
+        }
+    }
+
+    #[test]
+    fn newton_checked_reports_failures() {
+        let root = (X * X - 2.0).newton_checked(1.0, 20, 1e-6).unwrap();
+        assert_close(root, 2f32.sqrt(), 1e-6);
+
+        // Starts at the vertex of the parabola
+        let err = (X * X - 1.0).newton_checked(0.0, 20, 1e-6).unwrap_err();
+        assert_eq!(
+            err,
+            SolveError::ZeroDerivative {
+                estimate: 0.0,
+                residual: -1.0
+            }
+        );
+
+        // Cycles between 0 and 1
+        let err = (X.pow(3.0) - 2.0 * X + 2.0)
+            .newton_checked(0.0, 20, 1e-6)
+            .unwrap_err();
+        assert!(matches!(err, SolveError::NotConverged { .. }), "{err}");
+    }
+
+    #[test]
+    fn check_inverse_measures_round_trip_error() {
+        let inputs = [0.1, 0.5, 1.0, 2.0, 10.0];
+
+        assert!(X.exp().check_inverse(X.ln(), &inputs) < 1e-5);
+
+        // sqrt is not the inverse of exp, y = 10 comes back as e^sqrt(10)
+        let err = X.exp().check_inverse(X.sqrt(), &inputs);
+        assert_close(err, 10f32.sqrt().exp() - 10.0, 1e-4);
+    }
+
+    #[test]
+    fn mish_matches_reference() {
+        let mish = |x: f64| x * x.exp().ln_1p().tanh();
+
+        for x in [-5.0, -1.0, -0.3, 0.0, 0.5, 2.0, 8.0] {
+            let (y, dy) = X.mish().eval(x as f32);
+            let h = 1e-6;
+
+            assert_close(y, mish(x) as f32, 1e-6);
+            assert_close(dy, ((mish(x + h) - mish(x - h)) / (2.0 * h)) as f32, 1e-5);
+        }
+    }
+
+    #[test]
+    fn activation_looks_up_by_name() {
+        let mish = activation("mish").unwrap();
+
+        for x in [-2.0, 0.0, 0.7, 3.0] {
+            assert_eq!(mish(x), X.mish().eval(x));
+        }
+
+        let sigmoid = activation("sigmoid").unwrap();
+
+        for x in [-30.0, -2.0, 0.0, 0.7, 3.0] {
+            let s = 1.0 / (1.0 + (-x as f64).exp());
+            let (y, dy) = sigmoid(x);
+
+            assert_close(y, s as f32, 1e-6);
+            assert_close(dy, (s * (1.0 - s)) as f32, 1e-6);
+        }
+
+        assert_eq!(
+            activation("relu").unwrap_err(),
+            UnknownActivation("relu".to_string())
+        );
+    }
+
+    #[test]
+    fn sqrt_safe_has_finite_derivative_at_zero() {
+        let eps = 1e-6;
+        let f = X.sqrt_safe(eps);
+
+        for x in [1e-3, 1e-6, 1e-9, 0.0] {
+            let (_, dy) = f.eval(x);
+            assert!(dy.is_finite() && dy <= 0.5 / eps.sqrt(), "f'({x}) = {dy}");
+        }
+
+        for x in [0.25, 1.0, 9.0] {
+            let (y, dy) = X.sqrt().eval(x);
+            assert_close(f.eval(x).0, y, 1e-5);
+            assert_close(f.eval(x).1, dy, 1e-5);
+        }
+    }
+}
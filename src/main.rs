@@ -1,4 +1,4 @@
-use autodiff::{Fn, X};
+use autodiff::{base_pow, Fn, X};
 
 fn main() {
     {
@@ -24,7 +24,7 @@ fn main() {
     {
         println!("Solving for x^2 = 2^x");
         let f_x = X.pow(2.0);
-        let g_x = (X * f32::ln(2.0)).exp();
+        let g_x = base_pow(2.0, X);
 
         let mut input = 0.0;
 